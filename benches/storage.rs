@@ -0,0 +1,117 @@
+// Compares Map's chunked tile storage against `HashStorage`, which keeps a tile per key, both going
+// through the same Map calls. The `CHashMap` Map used to keep its tiles in is timed on its own as a
+// baseline, it can't hand out the tile pointers a `Storage` needs. Run with
+// `cargo bench --bench storage`.
+
+use std::time::{Duration, Instant};
+
+use chashmap::CHashMap;
+use grid_builder::{
+    Map,
+    WriteGuard,
+    generator::Generator,
+    region::Region,
+    storage::{ChunkedStorage, HashStorage, Storage},
+};
+
+const SIZE: i32 = 512;
+const CHUNK_SIZE: u32 = 32;
+const LOOKUPS: usize = 1 << 20;
+const ROUNDS: u32 = 5;
+
+#[derive(Clone)]
+struct Fill;
+
+impl<S: Storage<[i32; 2], u32>> Generator<[i32; 2], u32, S> for Fill {
+    fn generate(&mut self, chunk: &mut WriteGuard<'_, [i32; 2], u32, S>, core_region: &Region<[i32; 2]>, _umbra: &Region<[i32; 2]>) {
+        chunk.for_each_mut(core_region, |p, tile| *tile = (p[0] ^ p[1]) as u32);
+    }
+
+    fn umbra_width(&self) -> u32 {
+        0
+    }
+}
+
+// Deterministic points spread over the whole map
+fn lookups() -> Vec<[i32; 2]> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    (0..LOOKUPS).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        [(state % SIZE as u64) as i32, ((state >> 32) % SIZE as u64) as i32]
+    }).collect()
+}
+
+fn time<R>(name: &str, tiles: usize, mut f: impl FnMut() -> R) {
+    let mut best = Duration::MAX;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        std::hint::black_box(f());
+        best = best.min(start.elapsed());
+    }
+    println!("{:<32} {:>10.2?} {:>8.2} ns/tile", name, best, best.as_nanos() as f64 / tiles as f64);
+}
+
+fn generated<S: Storage<[i32; 2], u32> + 'static>(world: Region<[i32; 2]>) -> Map<[i32; 2], u32, S> {
+    let map = Map::with_storage(vec![Box::new(Fill)], CHUNK_SIZE, 0);
+    map.maybe_generate(world);
+    map
+}
+
+fn bench<S: Storage<[i32; 2], u32> + 'static>(storage: &str, points: &[[i32; 2]]) {
+    let world = Region::new([0, 0], [SIZE, SIZE]);
+    let tiles = world.area();
+
+    time(&format!("{}: generate", storage), tiles, || generated::<S>(world));
+
+    let map = generated::<S>(world);
+    time(&format!("{}: iterate region", storage), tiles, || {
+        map.region(&world).iter().map(|(_, tile)| *tile as u64).sum::<u64>()
+    });
+    time(&format!("{}: random reads", storage), LOOKUPS, || {
+        let region = map.region(&world);
+        points.iter().map(|p| *region.get(p).unwrap() as u64).sum::<u64>()
+    });
+    time(&format!("{}: random writes", storage), LOOKUPS, || {
+        let mut region = map.region_mut(&world);
+        for p in points {
+            *region.get_mut(p).unwrap() += 1;
+        }
+    });
+}
+
+// The same steps straight on a `CHashMap`, locking each tile on its own
+fn bench_chashmap(points: &[[i32; 2]]) {
+    let world = Region::new([0, 0], [SIZE, SIZE]);
+    let tiles = world.area();
+    let generated = || {
+        let map = CHashMap::new();
+        for p in world.points() {
+            map.insert(p, (p[0] ^ p[1]) as u32);
+        }
+        map
+    };
+
+    time("chashmap: generate", tiles, generated);
+
+    let map = generated();
+    time("chashmap: iterate region", tiles, || {
+        world.points().iter().map(|p| *map.get(p).unwrap() as u64).sum::<u64>()
+    });
+    time("chashmap: random reads", LOOKUPS, || {
+        points.iter().map(|p| *map.get(p).unwrap() as u64).sum::<u64>()
+    });
+    time("chashmap: random writes", LOOKUPS, || {
+        for p in points {
+            *map.get_mut(p).unwrap() += 1;
+        }
+    });
+}
+
+fn main() {
+    let points = lookups();
+    bench::<ChunkedStorage<_, _>>("chunked", &points);
+    bench::<HashStorage<_, _>>("hash", &points);
+    bench_chashmap(&points);
+}
@@ -1,7 +1,9 @@
 use std::collections::{HashSet, HashMap,};
 
 use super::{
-    generator::Generator, WriteGuard, point::Point,
+    generator::Generator, WriteGuard, point::Point, region::Region,
+    neighbourhood::{self, Neighbourhood, VonNeumann, CornerCutting},
+    storage::Storage,
 };
 
 pub trait Passable {
@@ -14,25 +16,286 @@ pub trait Connected<Point> {
     fn get_edges_mut(&mut self) -> &mut HashSet<Point>;
 }
 
-pub struct Connectivity;
-
-impl<P: Point, T: Connected<P> + Passable> Generator<P, T> for Connectivity {
-    fn generate(&mut self, chunk: &mut WriteGuard<'_, P, T>, core_region: &[P; 2], _umbra: &[P; 2]) {
-        let mut to_add = HashMap::new();
-        for p in P::points_in_region(core_region) {
-            let tile = chunk.get(&p).unwrap();
-            if tile.is_passable() {
-                for pp in p.neighboors() {
-                    let other = chunk.get(&pp).unwrap();
-                    if other.is_passable() {
-                        to_add.entry(p.clone()).or_insert(HashSet::new()).insert(pp.clone());
-                        to_add.entry(pp).or_insert(HashSet::new()).insert(p.clone());
-                    }
+// Tiles which can be climbed from to reach the tile directly above them (+z)
+pub trait Stairs {
+    fn has_stairs_up(&self) -> bool;
+}
+
+fn connect<P: Point, T: Connected<P> + Passable, S: Storage<P, T>>(
+    chunk: &mut WriteGuard<'_, P, T, S>,
+    core_region: &Region<P>,
+    neighbourhood: &dyn Neighbourhood<P>,
+    corner_cutting: CornerCutting,
+    can_connect: impl Fn(&P, &T, &P, &T) -> bool,
+) {
+    let mut to_add = HashMap::new();
+    for p in P::points_in_region(core_region) {
+        let tile = chunk.get(&p).unwrap();
+        for pp in neighbourhood.neighbours(&p) {
+            // Neighboors in chunks that haven't been generated yet are skipped, they'll connect
+            // back to this chunk when they are generated
+            if let Ok(other) = chunk.get(&pp) {
+                if can_connect(&p, tile, &pp, other) && can_cut_corner(chunk, &p, &pp, corner_cutting) {
+                    to_add.entry(p.clone()).or_insert_with(HashSet::new).insert(pp.clone());
+                    to_add.entry(pp).or_insert_with(HashSet::new).insert(p.clone());
                 }
             }
         }
-        for (p, edges) in to_add {
-            chunk.get_mut(&p).unwrap().get_edges_mut().extend(edges);
+    }
+    for (p, edges) in to_add {
+        if let Ok(tile) = chunk.get_mut(&p) {
+            tile.get_edges_mut().extend(edges);
+        }
+    }
+}
+
+// Corner tiles which aren't loaded count as blocked, the step is made from the other side once
+// they are
+fn can_cut_corner<P: Point, T: Passable, S: Storage<P, T>>(chunk: &WriteGuard<'_, P, T, S>, a: &P, b: &P, corner_cutting: CornerCutting) -> bool {
+    if corner_cutting == CornerCutting::Always {
+        return true;
+    }
+    let corners = neighbourhood::corners(a, b);
+    if corners.is_empty() {
+        return true;
+    }
+    let mut open = corners.iter().map(|p| chunk.get(p).map(|tile| tile.is_passable()).unwrap_or(false));
+    if corner_cutting == CornerCutting::IfEitherOpen {
+        open.any(|open| open)
+    } else {
+        open.all(|open| open)
+    }
+}
+
+// Links passable tiles to their passable neighbours, 4-connected unless given another
+// neighbourhood. This used to be a unit struct, `Connectivity::new()` (or `default()`) links the
+// same tiles the old `Connectivity` did.
+#[derive(Clone, Default)]
+pub struct Connectivity<N = VonNeumann> {
+    neighbourhood: N,
+    corner_cutting: CornerCutting,
+}
+
+impl Connectivity {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<N> Connectivity<N> {
+    pub fn with_neighbourhood<M>(self, neighbourhood: M) -> Connectivity<M> {
+        Connectivity {
+            neighbourhood,
+            corner_cutting: self.corner_cutting,
+        }
+    }
+
+    pub fn with_corner_cutting(mut self, corner_cutting: CornerCutting) -> Self {
+        self.corner_cutting = corner_cutting;
+        self
+    }
+}
+
+impl<P, T, S, N> Generator<P, T, S> for Connectivity<N>
+where
+    P: Point,
+    T: Connected<P> + Passable,
+    S: Storage<P, T>,
+    N: Neighbourhood<P> + Clone + 'static,
+{
+    fn generate(&mut self, chunk: &mut WriteGuard<'_, P, T, S>, core_region: &Region<P>, _umbra: &Region<P>) {
+        connect(chunk, core_region, &self.neighbourhood, self.corner_cutting, |_, tile, _, other| {
+            tile.is_passable() && other.is_passable()
+        });
+    }
+
+    fn umbra_width(&self) -> u32 {
+        self.neighbourhood.reach()
+    }
+}
+
+// Connectivity for voxel worlds where levels are only joined by stairs. Tiles on the same level
+// connect if both are passable, tiles on adjacent levels only if the lower one has stairs up.
+// Like `Connectivity` this is built with `new()` now rather than being a unit struct.
+#[derive(Clone, Default)]
+pub struct LayeredConnectivity<N = VonNeumann> {
+    neighbourhood: N,
+    corner_cutting: CornerCutting,
+}
+
+impl LayeredConnectivity {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<N> LayeredConnectivity<N> {
+    pub fn with_neighbourhood<M>(self, neighbourhood: M) -> LayeredConnectivity<M> {
+        LayeredConnectivity {
+            neighbourhood,
+            corner_cutting: self.corner_cutting,
         }
     }
+
+    pub fn with_corner_cutting(mut self, corner_cutting: CornerCutting) -> Self {
+        self.corner_cutting = corner_cutting;
+        self
+    }
+}
+
+impl<T, S, N> Generator<[i32; 3], T, S> for LayeredConnectivity<N>
+where
+    T: Connected<[i32; 3]> + Passable + Stairs,
+    S: Storage<[i32; 3], T>,
+    N: Neighbourhood<[i32; 3]> + Clone + 'static,
+{
+    fn generate(&mut self, chunk: &mut WriteGuard<'_, [i32; 3], T, S>, core_region: &Region<[i32; 3]>, _umbra: &Region<[i32; 3]>) {
+        connect(chunk, core_region, &self.neighbourhood, self.corner_cutting, |p, tile, pp, other| {
+            if !tile.is_passable() || !other.is_passable() {
+                return false;
+            }
+            match pp[2] - p[2] {
+                1 => tile.has_stairs_up(),
+                -1 => other.has_stairs_up(),
+                _ => true,
+            }
+        });
+    }
+
+    fn umbra_width(&self) -> u32 {
+        self.neighbourhood.reach()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Map;
+    use crate::neighbourhood::{Moore, Knight};
+
+    #[derive(Default)]
+    struct Voxel {
+        passable: bool,
+        stairs: bool,
+        edges: HashSet<[i32; 3]>,
+    }
+
+    impl Passable for Voxel {
+        fn is_passable(&self) -> bool {
+            self.passable
+        }
+
+        fn set_passable(&mut self, passable: bool) {
+            self.passable = passable;
+        }
+    }
+
+    impl Connected<[i32; 3]> for Voxel {
+        fn get_edges(&self) -> &HashSet<[i32; 3]> {
+            &self.edges
+        }
+
+        fn get_edges_mut(&mut self) -> &mut HashSet<[i32; 3]> {
+            &mut self.edges
+        }
+    }
+
+    impl Stairs for Voxel {
+        fn has_stairs_up(&self) -> bool {
+            self.stairs
+        }
+    }
+
+    // Open floors on every level with a single staircase at (1, 1)
+    #[derive(Clone)]
+    struct Floors;
+
+    impl Generator<[i32; 3], Voxel> for Floors {
+        fn generate(&mut self, chunk: &mut WriteGuard<'_, [i32; 3], Voxel>, core_region: &Region<[i32; 3]>, _umbra: &Region<[i32; 3]>) {
+            for p in <[i32; 3]>::points_in_region(core_region) {
+                let tile = chunk.get_mut(&p).unwrap();
+                tile.set_passable(true);
+                tile.stairs = p[0] == 1 && p[1] == 1;
+            }
+        }
+    }
+
+    #[test]
+    fn levels_connect_through_stairs() {
+        let map: Map<[i32; 3], Voxel> = Map::new(vec![Box::new(Floors), Box::new(LayeredConnectivity::new())], 4, 0);
+        map.maybe_generate(Region::new([0, 0, 0], [4, 4, 8]));
+
+        let stairs = map.get(&[1, 1, 3]);
+        assert!(stairs.get_edges().contains(&[1, 1, 4]));
+        assert!(stairs.get_edges().contains(&[1, 1, 2]));
+        assert!(stairs.get_edges().contains(&[2, 1, 3]));
+
+        let floor = map.get(&[2, 2, 3]);
+        assert!(!floor.get_edges().contains(&[2, 2, 4]));
+        assert!(!floor.get_edges().contains(&[2, 2, 2]));
+        assert_eq!(floor.get_edges().len(), 4);
+    }
+
+    #[derive(Default)]
+    struct Cell {
+        passable: bool,
+        edges: HashSet<[i32; 2]>,
+    }
+
+    impl Passable for Cell {
+        fn is_passable(&self) -> bool {
+            self.passable
+        }
+
+        fn set_passable(&mut self, passable: bool) {
+            self.passable = passable;
+        }
+    }
+
+    impl Connected<[i32; 2]> for Cell {
+        fn get_edges(&self) -> &HashSet<[i32; 2]> {
+            &self.edges
+        }
+
+        fn get_edges_mut(&mut self) -> &mut HashSet<[i32; 2]> {
+            &mut self.edges
+        }
+    }
+
+    #[derive(Clone)]
+    struct Walls(Vec<[i32; 2]>);
+
+    impl Generator<[i32; 2], Cell> for Walls {
+        fn generate(&mut self, chunk: &mut WriteGuard<'_, [i32; 2], Cell>, core_region: &Region<[i32; 2]>, _umbra: &Region<[i32; 2]>) {
+            for p in <[i32; 2]>::points_in_region(core_region) {
+                chunk.get_mut(&p).unwrap().set_passable(!self.0.contains(&p));
+            }
+        }
+    }
+
+    fn connected(connectivity: impl Generator<[i32; 2], Cell> + 'static, a: [i32; 2], b: [i32; 2]) -> bool {
+        let map: Map<[i32; 2], Cell> = Map::new(vec![Box::new(Walls(vec![[4, 3]])), Box::new(connectivity)], 4, 0);
+        map.maybe_generate(Region::new([0, 0], [8, 8]));
+        let connected = map.get(&a).get_edges().contains(&b);
+        assert_eq!(connected, map.get(&b).get_edges().contains(&a));
+        connected
+    }
+
+    #[test]
+    fn diagonals_and_corner_cutting() {
+        assert!(!connected(Connectivity::new(), [3, 3], [4, 4]));
+        assert!(connected(Connectivity::new().with_neighbourhood(Moore), [3, 3], [4, 4]));
+        assert!(connected(Connectivity::new().with_neighbourhood(Moore).with_corner_cutting(CornerCutting::IfEitherOpen), [3, 3], [4, 4]));
+        assert!(!connected(Connectivity::new().with_neighbourhood(Moore).with_corner_cutting(CornerCutting::IfBothOpen), [3, 3], [4, 4]));
+        assert!(connected(Connectivity::new().with_neighbourhood(Moore).with_corner_cutting(CornerCutting::IfBothOpen), [2, 2], [3, 3]));
+    }
+
+    #[test]
+    fn knight_moves_cross_chunks() {
+        let map: Map<[i32; 2], Cell> = Map::new(vec![Box::new(Walls(vec![])), Box::new(Connectivity::new().with_neighbourhood(Knight))], 4, 0);
+        map.maybe_generate(Region::new([0, 0], [8, 8]));
+        let tile = map.get(&[3, 3]);
+        assert_eq!(tile.get_edges().len(), 8);
+        assert!(tile.get_edges().contains(&[5, 4]));
+    }
 }
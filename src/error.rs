@@ -0,0 +1,44 @@
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    // The point is outside the region the guard was taken for
+    OutOfRegion,
+    // The chunk containing the point has not been generated
+    NotGenerated,
+    // The chunk containing the point was generated but has since been evicted
+    Evicted,
+    // Another guard held an overlapping region for longer than the timeout
+    LockTimeout,
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::OutOfRegion => write!(f, "point is outside the guarded region"),
+            Error::NotGenerated => write!(f, "chunk has not been generated"),
+            Error::Evicted => write!(f, "chunk has been evicted"),
+            Error::LockTimeout => write!(f, "timed out waiting for a region lock"),
+            Error::Io(e) => write!(f, "chunk store error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
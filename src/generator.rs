@@ -1,27 +1,79 @@
 use crate::{
     WriteGuard,
     point::Point,
+    region::Region,
+    seed::derive_seed,
+    storage::{ChunkedStorage, Storage},
 };
-pub trait Generator<P, T>: Send where P: Point {
-    fn generate(&mut self, chunk: &mut WriteGuard<'_, P, T>, core_region: &[P; 2], umbra: &[P; 2]);
+// Generators are written against the default storage unless they're generic over `S`. Map runs
+// clones of the generators it was given, taken afresh for every batch of chunks and for every
+// rayon worker, so any state a generator builds up in `generate` is lost between calls. Keep
+// what has to persist in what `reseed` sets up or behind shared ownership.
+pub trait Generator<P, T, S = ChunkedStorage<P, T>>: GeneratorClone<P, T, S> + Send where P: Point, S: Storage<P, T> {
+    fn generate(&mut self, chunk: &mut WriteGuard<'_, P, T, S>, core_region: &Region<P>, umbra: &Region<P>);
+
+    // Called by Map with a seed derived from the world seed so that generation is reproducible
+    fn reseed(&mut self, _seed: u64) {}
+
+    // How far outside the core region the generator reads or writes tiles. Before it runs, Map
+    // takes every chunk within this margin through the generators before it, so what it sees in
+    // the umbra is the same whichever order chunks are requested in.
+    fn umbra_width(&self) -> u32 {
+        1
+    }
+}
+
+// Map hands each worker its own copy of the generators so any Clone generator gets this for free
+pub trait GeneratorClone<P, T, S> where P: Point, S: Storage<P, T> {
+    fn clone_box(&self) -> Box<dyn Generator<P, T, S>>;
+}
+
+impl<P: Point, T, S: Storage<P, T>, G: Generator<P, T, S> + Clone + 'static> GeneratorClone<P, T, S> for G {
+    fn clone_box(&self) -> Box<dyn Generator<P, T, S>> {
+        Box::new(self.clone())
+    }
+}
+
+impl<P: Point, T, S: Storage<P, T>> Clone for Box<dyn Generator<P, T, S>> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
 }
 
-pub struct GeneratorSequence<P, T> where P: Point {
-    generators: Vec<Box<dyn Generator<P, T>>>,
+pub struct GeneratorSequence<P, T, S = ChunkedStorage<P, T>> where P: Point, S: Storage<P, T> {
+    generators: Vec<Box<dyn Generator<P, T, S>>>,
 }
 
-impl<P: Point, T> GeneratorSequence<P, T> {
-    pub fn new(generators: Vec<Box<dyn Generator<P, T>>>) -> Self {
+impl<P: Point, T, S: Storage<P, T>> Clone for GeneratorSequence<P, T, S> {
+    fn clone(&self) -> Self {
+        Self {
+            generators: self.generators.clone(),
+        }
+    }
+}
+
+impl<P: Point, T, S: Storage<P, T>> GeneratorSequence<P, T, S> {
+    pub fn new(generators: Vec<Box<dyn Generator<P, T, S>>>) -> Self {
         Self {
             generators,
         }
     }
 }
 
-impl<P: Point, T> Generator<P, T> for GeneratorSequence<P, T> {
-    fn generate(&mut self, chunk: &mut WriteGuard<'_, P, T>, core_region: &[P; 2], umbra: &[P; 2]) {
+impl<P: Point + 'static, T: 'static, S: Storage<P, T> + 'static> Generator<P, T, S> for GeneratorSequence<P, T, S> {
+    fn generate(&mut self, chunk: &mut WriteGuard<'_, P, T, S>, core_region: &Region<P>, umbra: &Region<P>) {
         for generator in &mut self.generators {
             generator.generate(chunk, core_region, umbra);
         }
     }
+
+    fn umbra_width(&self) -> u32 {
+        self.generators.iter().map(|generator| generator.umbra_width()).max().unwrap_or(0)
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        for (i, generator) in self.generators.iter_mut().enumerate() {
+            generator.reseed(derive_seed(seed, &i));
+        }
+    }
 }
@@ -0,0 +1,248 @@
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+use crate::{
+    point::Point,
+    region::Region,
+};
+
+// A hex in axial coordinates (pointy topped). Regions are rectangles in "odd-r" offset
+// coordinates, so chunks and regions are the familiar row/column blocks of a hex map rather than
+// the rhombuses you would get by boxing the axial coordinates directly.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Hex {
+    pub q: i32,
+    pub r: i32,
+}
+
+const DIRECTIONS: [(i32, i32); 6] = [(1, 0), (1, -1), (0, -1), (-1, 0), (-1, 1), (0, 1)];
+
+impl Hex {
+    pub fn new(q: i32, r: i32) -> Self {
+        Self {
+            q,
+            r,
+        }
+    }
+
+    pub fn from_offset(col: i32, row: i32) -> Self {
+        Self::new(col - (row - (row & 1)) / 2, row)
+    }
+
+    pub fn to_offset(&self) -> [i32; 2] {
+        [self.q + (self.r - (self.r & 1)) / 2, self.r]
+    }
+
+    pub fn s(&self) -> i32 {
+        -self.q - self.r
+    }
+
+    pub fn distance(&self, other: &Self) -> u32 {
+        (((self.q - other.q).abs() + (self.r - other.r).abs() + (self.s() - other.s()).abs()) / 2) as u32
+    }
+
+    fn step(&self, direction: usize, n: i32) -> Self {
+        let (dq, dr) = DIRECTIONS[direction];
+        Self::new(self.q + dq * n, self.r + dr * n)
+    }
+
+    // Every hex exactly `radius` steps away
+    pub fn ring(&self, radius: u32) -> impl Iterator<Item=Hex> {
+        let mut hexes = vec![];
+        if radius == 0 {
+            hexes.push(*self);
+        } else {
+            let mut hex = self.step(4, radius as i32);
+            for direction in 0..6 {
+                for _ in 0..radius {
+                    hexes.push(hex);
+                    hex = hex.step(direction, 1);
+                }
+            }
+        }
+        hexes.into_iter()
+    }
+
+    // Every hex within `radius` steps, ordered by distance
+    pub fn spiral(&self, radius: u32) -> impl Iterator<Item=Hex> {
+        let center = *self;
+        (0..=radius).flat_map(move |r| center.ring(r))
+    }
+}
+
+fn to_offset_rect(r: &Region<Hex>) -> Region<[i32; 2]> {
+    Region::new(r.min.to_offset(), r.max.to_offset())
+}
+
+fn from_offset_rect(r: &Region<[i32; 2]>) -> Region<Hex> {
+    Region::new(Hex::from_offset(r.min[0], r.min[1]), Hex::from_offset(r.max[0], r.max[1]))
+}
+
+impl Point for Hex {
+    fn to_cube(&self, size: u32) -> Region<Self> {
+        from_offset_rect(&self.to_offset().to_cube(size))
+    }
+
+    fn overlap_rect(a: &Region<Self>, other: &Region<Self>) -> bool {
+        <[i32; 2]>::overlap_rect(&to_offset_rect(a), &to_offset_rect(other))
+    }
+
+    fn intersect(a: &Region<Self>, other: &Region<Self>) -> Option<Region<Self>> {
+        <[i32; 2]>::intersect(&to_offset_rect(a), &to_offset_rect(other)).as_ref().map(from_offset_rect)
+    }
+
+    fn bounding_box(a: &Region<Self>, other: &Region<Self>) -> Region<Self> {
+        from_offset_rect(&<[i32; 2]>::bounding_box(&to_offset_rect(a), &to_offset_rect(other)))
+    }
+
+    fn area(r: &Region<Self>) -> usize {
+        <[i32; 2]>::area(&to_offset_rect(r))
+    }
+
+    fn split(r: &Region<Self>) -> Option<[Region<Self>; 2]> {
+        <[i32; 2]>::split(&to_offset_rect(r)).map(|[a, b]| [from_offset_rect(&a), from_offset_rect(&b)])
+    }
+
+    fn difference(a: &Region<Self>, other: &Region<Self>) -> Vec<Region<Self>> {
+        <[i32; 2]>::difference(&to_offset_rect(a), &to_offset_rect(other)).iter().map(from_offset_rect).collect()
+    }
+
+    fn expand(r: &Region<Self>, margin: u32) -> Region<Self> {
+        from_offset_rect(&<[i32; 2]>::expand(&to_offset_rect(r), margin))
+    }
+
+    fn contained(&self, r: &Region<Self>) -> bool {
+        self.to_offset().contained(&to_offset_rect(r))
+    }
+
+    fn chunk_index(&self, chunk_size: u32) -> (Self, usize) {
+        let (c, i) = self.to_offset().chunk_index(chunk_size);
+        (Hex::from_offset(c[0], c[1]), i)
+    }
+
+    fn max_unrolled_index(chunk_size: u32) -> usize {
+        <[i32; 2]>::max_unrolled_index(chunk_size)
+    }
+
+    fn chunks_in_region(r: &Region<Self>, chunk_size: u32) -> Vec<Region<Self>> {
+        <[i32; 2]>::chunks_in_region(&to_offset_rect(r), chunk_size).iter().map(from_offset_rect).collect()
+    }
+
+    fn points_in_region(r: &Region<Self>) -> Vec<Self> {
+        <[i32; 2]>::points_in_region(&to_offset_rect(r)).into_iter().map(|p| Hex::from_offset(p[0], p[1])).collect()
+    }
+
+    fn neighboors(&self) -> Vec<Self> {
+        (0..6).map(|direction| self.step(direction, 1)).collect()
+    }
+
+    // Distance to the hex in the region nearest to this one in offset space, which is close to
+    // but not always exactly the nearest hex
+    fn distance_to_region(&self, r: &Region<Self>) -> f64 {
+        let r = to_offset_rect(r);
+        let p = self.to_offset();
+        let nearest = Hex::from_offset(
+            p[0].max(r.min[0]).min(r.max[0] - 1),
+            p[1].max(r.min[1]).min(r.max[1] - 1),
+        );
+        self.distance(&nearest) as f64
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        Hex::new(self.q + other.q, self.r + other.r)
+    }
+
+    fn mul(&self, m: i32) -> Self {
+        Hex::new(self.q * m, self.r * m)
+    }
+
+    fn div(&self, m: i32) -> Self {
+        Hex::new(self.q / m, self.r / m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Map, WriteGuard,
+        generator::Generator,
+        sparse::SparseMap,
+    };
+
+    #[test]
+    fn offset_round_trip() {
+        for hex in Hex::new(3, -2).spiral(5) {
+            let [col, row] = hex.to_offset();
+            assert_eq!(Hex::from_offset(col, row), hex);
+        }
+    }
+
+    #[test]
+    fn neighboors_are_adjacent() {
+        let hex = Hex::new(-4, 7);
+        let neighboors = hex.neighboors();
+        assert_eq!(neighboors.len(), 6);
+        assert!(neighboors.iter().all(|other| hex.distance(other) == 1));
+    }
+
+    #[test]
+    fn rings_and_spirals() {
+        let hex = Hex::new(2, 1);
+        for radius in 0..5 {
+            let ring: Vec<Hex> = hex.ring(radius).collect();
+            assert_eq!(ring.len(), if radius == 0 { 1 } else { 6 * radius as usize });
+            assert!(ring.iter().all(|other| hex.distance(other) == radius));
+        }
+        let spiral: Vec<Hex> = hex.spiral(3).collect();
+        assert_eq!(spiral.len(), 37);
+        assert!(spiral.windows(2).all(|w| hex.distance(&w[0]) <= hex.distance(&w[1])));
+    }
+
+    #[test]
+    fn chunks_cover_region() {
+        let r = Region::new(Hex::from_offset(-5, -3), Hex::from_offset(7, 9));
+        let points = Hex::points_in_region(&r);
+        assert_eq!(points.len(), 12 * 12);
+        let chunks = Hex::chunks_in_region(&r, 4);
+        for p in &points {
+            let (origin, i) = p.chunk_index(4);
+            assert!(i < Hex::max_unrolled_index(4));
+            assert_eq!(chunks.iter().filter(|chunk| chunk.min == origin).count(), 1);
+            for neighboor in p.neighboors() {
+                assert!(neighboor.contained(&Hex::expand(&r, 1)));
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    struct Distance;
+
+    impl Generator<Hex, u32> for Distance {
+        fn generate(&mut self, chunk: &mut WriteGuard<'_, Hex, u32>, core_region: &Region<Hex>, _umbra: &Region<Hex>) {
+            for p in Hex::points_in_region(core_region) {
+                *chunk.get_mut(&p).unwrap() = p.distance(&Hex::default());
+            }
+        }
+    }
+
+    #[test]
+    fn hex_map() {
+        let map: Map<Hex, u32> = Map::new(vec![Box::new(Distance)], 8, 0);
+        let r = Region::new(Hex::from_offset(-10, -10), Hex::from_offset(10, 10));
+        map.maybe_generate(r);
+        let region = map.region(&r);
+        for hex in Hex::default().spiral(9) {
+            assert_eq!(*region.get(&hex).unwrap(), hex.distance(&Hex::default()));
+        }
+
+        let mut sparse: SparseMap<Hex, u32> = SparseMap::new(8);
+        let mut region = sparse.region_mut(&r);
+        for hex in Hex::new(-3, 2).ring(4) {
+            region.set(&hex, 1).unwrap();
+        }
+        assert_eq!(region.get(&Hex::new(-3, 6)).unwrap(), Some(&1));
+        assert_eq!(region.get(&Hex::new(-3, 2)).unwrap(), Some(&0));
+    }
+}
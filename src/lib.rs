@@ -1,19 +1,43 @@
-use std::sync::Mutex;
-use std::collections::HashSet;
+use std::any::{Any, TypeId};
+use std::cell::UnsafeCell;
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
 
-use log::debug;
+use log::warn;
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
 
-use chashmap::CHashMap;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
 
 use crate::{
     point::Point,
+    region::{Region, RegionSet},
     region_lock::{Lock as RegionLock, Guard},
+    storage::{ChunkedStorage, Storage, View},
 };
 
+pub use crate::error::Error;
+
 pub mod sparse;
+pub mod palette;
 pub mod region_lock;
+pub mod error;
 pub mod generator;
 pub mod point;
+pub mod neighbourhood;
+pub mod region;
+pub mod hex;
+pub mod wrapping;
+pub mod seed;
+pub mod store;
+pub mod storage;
 
 
 #[cfg(feature = "noise_based_generators")]
@@ -21,178 +45,1932 @@ pub mod noise_based_generators;
 //pub mod postprocessors
 pub mod analysis;
 
-struct Lock<P, T> {
-    generated: HashSet<[P; 2]>,
-    generators: Vec<Box<dyn generator::Generator<P, T>>>,
-    dirty_chunks: Vec<[P; 2]>,
+type Generators<P, T, S> = Vec<Box<dyn generator::Generator<P, T, S>>>;
+
+struct Lock<P, T, S> where P: Point, S: Storage<P, T> {
+    generated: HashSet<Region<P>>,
+    in_progress: HashSet<Region<P>>,
+    evicted: HashSet<Region<P>>,
+    modified: HashSet<Region<P>>,
+    last_used: HashMap<Region<P>, u64>,
+    clock: u64,
+    queued: Vec<Region<P>>,
+    foci: HashMap<usize, Focus<P>>,
+    // Chunks which are loaded but haven't been through every generator yet, with how many they
+    // have been through. Generated as the surroundings of chunks whose generators need a margin.
+    progress: HashMap<Region<P>, usize>,
+    advancing: HashSet<Region<P>>,
+    // Chunks a `maybe_generate` or `generate_chunks` call is waiting on, with how many, which
+    // mustn't be evicted from under it
+    pinned: HashMap<Region<P>, usize>,
+    // Live `GenerationRequest`s by id
+    requests: HashMap<usize, Pending<P>>,
+    next_request: usize,
+    generators: Generators<P, T, S>,
+    dirty_chunks: Vec<Region<P>>,
+    wakers: Vec<Waker>,
+    stop_workers: bool,
+}
+
+struct Focus<P> {
+    position: P,
+    // The chunks within the focus' radius, which it keeps queued
+    chunks: HashSet<Region<P>>,
 }
 
-pub struct Map<P, T> {
-    lock: Mutex<Lock<P, T>>,
+// What a request is still waiting for. Chunks are struck off as they're generated, so a chunk
+// which is evicted again afterwards still counts as done.
+struct Pending<P> {
+    chunks: HashSet<Region<P>>,
+    cancelled: bool,
+}
+
+impl<P: Point, T, S: Storage<P, T>> Lock<P, T, S> {
+    fn claim<'a>(&mut self, chunks: impl IntoIterator<Item=&'a Region<P>>) -> Vec<Region<P>> where P: 'a {
+        let mut claimed = vec![];
+        for chunk in chunks {
+            if !self.generated.contains(chunk) && self.in_progress.insert(chunk.clone()) {
+                claimed.push(chunk.clone());
+            }
+        }
+        claimed
+    }
+
+    // Copies of the generators to run on the claimed chunks, None when there's nothing to run
+    fn generators_for(&self, claimed: &[Region<P>]) -> Option<Generators<P, T, S>> {
+        if claimed.is_empty() {
+            None
+        } else {
+            Some(self.generators.clone())
+        }
+    }
+
+    fn stage(&self, chunk: &Region<P>, stages: usize) -> usize {
+        if self.generated.contains(chunk) {
+            stages
+        } else {
+            self.progress.get(chunk).copied().unwrap_or(0)
+        }
+    }
+
+    fn complete(&mut self, chunk: Region<P>) {
+        self.progress.remove(&chunk);
+        self.in_progress.remove(&chunk);
+        self.evicted.remove(&chunk);
+        self.generated.insert(chunk.clone());
+        self.dirty_chunks.push(chunk.clone());
+        self.resolve(&chunk);
+        self.touch(vec![chunk]);
+    }
+
+    fn resolve(&mut self, chunk: &Region<P>) {
+        for request in self.requests.values_mut() {
+            request.chunks.remove(chunk);
+        }
+    }
+
+    // Fully or partly generated
+    fn is_loaded(&self, chunk: &Region<P>) -> bool {
+        self.generated.contains(chunk) || self.progress.contains_key(chunk)
+    }
+
+    fn is_evictable(&self, chunk: &Region<P>) -> bool {
+        self.is_loaded(chunk) && !self.in_progress.contains(chunk) && !self.advancing.contains(chunk) && !self.pinned.contains_key(chunk)
+    }
+
+    fn pin(&mut self, chunks: &[Region<P>]) {
+        for chunk in chunks {
+            *self.pinned.entry(chunk.clone()).or_insert(0) += 1;
+        }
+    }
+
+    fn unpin(&mut self, chunks: &[Region<P>]) {
+        for chunk in chunks {
+            if let Some(count) = self.pinned.get_mut(chunk) {
+                *count -= 1;
+                if *count == 0 {
+                    self.pinned.remove(chunk);
+                }
+            }
+        }
+    }
+
+    fn mark_modified(&mut self, chunks: &[Region<P>]) {
+        for chunk in chunks {
+            if self.generated.contains(chunk) {
+                self.modified.insert(chunk.clone());
+            }
+        }
+    }
+
+    fn touch(&mut self, chunks: Vec<Region<P>>) {
+        self.clock += 1;
+        for chunk in chunks {
+            if self.generated.contains(&chunk) {
+                self.last_used.insert(chunk, self.clock);
+            }
+        }
+    }
+
+    fn distance_to_focus(&self, chunk: &Region<P>) -> f64 {
+        self.foci.values()
+            .map(|focus| focus.position.distance_to_region(chunk))
+            .fold(f64::INFINITY, f64::min)
+    }
+
+    fn enqueue(&mut self, chunks: &[Region<P>]) {
+        for chunk in chunks {
+            if !self.generated.contains(chunk) && !self.in_progress.contains(chunk) && !self.queued.contains(chunk) {
+                self.queued.push(chunk.clone());
+            }
+        }
+    }
+
+    // Whether a focus or a live request is still waiting for the chunk
+    fn is_wanted(&self, chunk: &Region<P>) -> bool {
+        self.foci.values().any(|focus| focus.chunks.contains(chunk)) ||
+        self.requests.values().any(|request| request.chunks.contains(chunk))
+    }
+
+    // Cancels those of the chunks nobody wants any more
+    fn abandon(&mut self, chunks: &HashSet<Region<P>>) {
+        if !chunks.is_empty() {
+            self.cancel_where(|lock, chunk| chunks.contains(chunk) && !lock.is_wanted(chunk));
+        }
+    }
+
+    // Orders chunks nearest-first relative to the closest focus point. Without any focus points
+    // the order is left alone.
+    fn prioritize(&self, chunks: &mut Vec<Region<P>>) {
+        if self.foci.is_empty() {
+            return;
+        }
+        let mut keyed: Vec<(f64, Region<P>)> = chunks.drain(..).map(|chunk| {
+            (self.distance_to_focus(&chunk), chunk)
+        }).collect();
+        keyed.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        chunks.extend(keyed.into_iter().map(|(_, chunk)| chunk));
+    }
+
+    fn cancel_where(&mut self, f: impl Fn(&Self, &Region<P>) -> bool) {
+        let queued = std::mem::take(&mut self.queued);
+        let (cancelled, queued): (Vec<Region<P>>, Vec<Region<P>>) = queued.into_iter().partition(|chunk| f(self, chunk));
+        self.queued = queued;
+        for request in self.requests.values_mut() {
+            if cancelled.iter().any(|chunk| request.chunks.contains(chunk)) {
+                request.cancelled = true;
+            }
+        }
+        if !cancelled.is_empty() {
+            for waker in self.wakers.drain(..) {
+                waker.wake();
+            }
+        }
+    }
+}
+
+pub struct Map<P, T, S = ChunkedStorage<P, T>> where P: Point, S: Storage<P, T> {
+    lock: Mutex<Lock<P, T, S>>,
+    // Notified whenever chunks finish generating or new chunks are queued
+    signal: Condvar,
 
     chunk_size: u32,
+    seed: u64,
+
+    // Shared with the map's layers
+    region_lock: Arc<RegionLock<P>>,
+    tiles: S,
+    chunk_data: Mutex<HashMap<Region<P>, Arc<ChunkData>>>,
+    subscribers: Mutex<Vec<Subscriber<P>>>,
+    store: Option<Box<dyn store::ChunkStore<P, T>>>,
+    // The map this one is a layer of, see `new_layer`
+    base: Option<Arc<dyn Base<P>>>,
+    layers: AtomicUsize,
+}
+
+// Values attached to a loaded chunk, at most one of each type. Like tiles they're only touched
+// under a region lock, writing one takes a write lock on the whole chunk.
+#[derive(Default)]
+struct ChunkData(UnsafeCell<HashMap<TypeId, Box<dyn Any + Send + Sync>>>);
+
+unsafe impl Sync for ChunkData {}
+
+struct Subscriber<P> {
+    region: Region<P>,
+    sender: Sender<RegionSet<P>>,
+}
+
+// Sends each subscriber the part of the change inside its region. Subscribers whose receiver has
+// been dropped are forgotten.
+fn notify<P: Point>(subscribers: &Mutex<Vec<Subscriber<P>>>, changed: &RegionSet<P>) {
+    subscribers.lock().unwrap().retain(|subscriber| {
+        let changed = changed.intersection(&RegionSet::from(&subscriber.region));
+        changed.is_empty() || subscriber.sender.send(changed).is_ok()
+    });
+}
+
+// What a layer needs from the map it was made from
+trait Base<P: Point>: Send + Sync {
+    fn maybe_generate(&self, regions: RegionSet<P>);
+}
+
+impl<P: Point, T: Default + Send + Sync, S: Storage<P, T>> Base<P> for Map<P, T, S> {
+    fn maybe_generate(&self, regions: RegionSet<P>) {
+        Map::maybe_generate(self, regions)
+    }
+}
+
+const WORKER_BATCH_SIZE: usize = 16;
 
-    region_lock: RegionLock<P>,
-    map: CHashMap<P, T>,
+impl<P: Point, T: Default + Send + Sync> Map<P, T> {
+    pub fn new(generators: Vec<Box<dyn generator::Generator<P, T>>>, chunk_size: u32, seed: u64) -> Self {
+        Self::with_storage(generators, chunk_size, seed)
+    }
 }
 
-impl<P: Point, T: Default> Map<P, T> {
-    pub fn new(generators: Vec<Box<dyn generator::Generator<P, T>>>, chunk_size: u32) -> Self {
+impl<P: Point, T: Default + Send + Sync, S: Storage<P, T>> Map<P, T, S> {
+    // For maps keeping their tiles in something other than `ChunkedStorage`, e.g.
+    // `Map::<_, _, HashStorage<_, _>>::with_storage(..)`. Generators have to be written for the
+    // storage as well.
+    pub fn with_storage(generators: Vec<Box<dyn generator::Generator<P, T, S>>>, chunk_size: u32, seed: u64) -> Self {
+        Self::layered(generators, chunk_size, seed, Arc::new(RegionLock::new()), None)
+    }
+
+    // A map on the same chunk grid as this one with its own tile type, storage and generators,
+    // e.g. objects or lighting over terrain. The two share a region lock, so a guard on either
+    // blocks overlapping guards on both, and a guard on one can hand out the other's tiles under
+    // the lock it holds, see `ReadGuard::layer`. Before the layer generates a chunk, this map
+    // generates the chunks within reach of the layer's generators.
+    pub fn new_layer<U, S2>(self: &Arc<Self>, generators: Vec<Box<dyn generator::Generator<P, U, S2>>>) -> Map<P, U, S2>
+    where P: 'static, T: 'static, S: 'static, U: Default + Send + Sync, S2: Storage<P, U> {
+        let layer = self.layers.fetch_add(1, Ordering::Relaxed);
+        let base: Arc<dyn Base<P>> = self.clone();
+        Map::layered(generators, self.chunk_size, seed::derive_seed(self.seed, &("layer", layer)), self.region_lock.clone(), Some(base))
+    }
+
+    fn layered(mut generators: Vec<Box<dyn generator::Generator<P, T, S>>>, chunk_size: u32, seed: u64, region_lock: Arc<RegionLock<P>>, base: Option<Arc<dyn Base<P>>>) -> Self {
+        P::check_chunk_size(chunk_size);
+        for (i, generator) in generators.iter_mut().enumerate() {
+            generator.reseed(seed::derive_seed(seed, &i));
+        }
         Self {
             lock: Mutex::new(Lock {
                 generated: HashSet::new(),
+                in_progress: HashSet::new(),
+                evicted: HashSet::new(),
+                modified: HashSet::new(),
+                last_used: HashMap::new(),
+                clock: 0,
+                queued: vec![],
+                foci: HashMap::new(),
+                progress: HashMap::new(),
+                advancing: HashSet::new(),
+                pinned: HashMap::new(),
+                requests: HashMap::new(),
+                next_request: 0,
                 generators,
                 dirty_chunks: vec![],
+                wakers: vec![],
+                stop_workers: false,
             }),
+            signal: Condvar::new(),
 
             chunk_size,
+            seed,
 
-            region_lock: RegionLock::new(),
-            map: CHashMap::new(),
+            region_lock,
+            tiles: S::new(chunk_size),
+            chunk_data: Mutex::new(HashMap::new()),
+            subscribers: Mutex::new(vec![]),
+            store: None,
+            base,
+            layers: AtomicUsize::new(0),
         }
     }
 
-    pub fn maybe_generate(&self, r: &[P; 2]) {
+    // Chunks found in the store are loaded instead of generated. Chunks which have been modified
+    // through `get_mut` or `region_mut` are written back to it when evicted or flushed.
+    pub fn with_store(mut self, store: Box<dyn store::ChunkStore<P, T>>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    // Accepts a single region or a `RegionSet`, e.g. the view minus `generated_regions()`
+    pub fn maybe_generate<R: Into<RegionSet<P>>>(&self, r: R) {
+        let chunks = r.into().chunks(self.chunk_size);
+        let (claimed, generators) = {
+            let mut lock = self.lock.lock().unwrap();
+            // Until they're all generated, so none can be evicted while this waits for the rest
+            lock.pin(&chunks);
+            let mut claimed = lock.claim(&chunks);
+            lock.prioritize(&mut claimed);
+            lock.queued.retain(|chunk| !claimed.contains(chunk));
+            let generators = lock.generators_for(&claimed);
+            (claimed, generators)
+        };
+        if let Some(generators) = generators {
+            self.generate_chunks(generators, claimed);
+        }
+
+        // Some of the chunks may have been claimed by another thread, wait for those as well
         let mut lock = self.lock.lock().unwrap();
-        let chunks:HashSet<[P; 2]> = P::chunks_in_region(r, self.chunk_size).into_iter().collect();
-        let to_generate:HashSet<[P; 2]> = chunks.difference(&lock.generated).cloned().collect();
-        lock.dirty_chunks.extend(to_generate.iter().cloned());
-        for chunk in &to_generate {
-            let umbra = P::expand(chunk, 1);
-            let region_lock = self.region_lock.write_region(&[umbra.clone()]);
+        while !chunks.iter().all(|chunk| lock.generated.contains(chunk)) {
+            lock = self.signal.wait(lock).unwrap();
+        }
+        lock.unpin(&chunks);
+    }
+
+    // Queues the chunks in the region for generation and returns immediately. Queued chunks are
+    // generated by `generate_queued` or `run_worker`, and those still queued when the request is
+    // dropped are cancelled unless something else wants them.
+    pub fn request<R: Into<RegionSet<P>>>(&self, r: R) -> GenerationRequest<'_, P, T, S> {
+        let chunks = r.into().chunks(self.chunk_size);
+        let mut lock = self.lock.lock().unwrap();
+        lock.enqueue(&chunks);
+        let id = lock.next_request;
+        lock.next_request += 1;
+        let pending = chunks.iter().filter(|chunk| !lock.generated.contains(*chunk)).cloned().collect();
+        lock.requests.insert(id, Pending {
+            chunks: pending,
+            cancelled: false,
+        });
+        self.signal.notify_all();
+        GenerationRequest {
+            map: self,
+            id,
+            chunks,
+        }
+    }
+
+    // Generates up to `max_chunks` queued chunks and returns how many were generated
+    pub fn generate_queued(&self, max_chunks: usize) -> usize {
+        let (claimed, generators) = {
+            let mut lock = self.lock.lock().unwrap();
+            let mut queued = std::mem::take(&mut lock.queued);
+            lock.prioritize(&mut queued);
+            lock.queued = queued;
+            let n = max_chunks.min(lock.queued.len());
+            let chunks: Vec<Region<P>> = lock.queued.drain(..n).collect();
+            let claimed = lock.claim(&chunks);
+            let generators = lock.generators_for(&claimed);
+            (claimed, generators)
+        };
+        let n = claimed.len();
+        if let Some(generators) = generators {
+            self.generate_chunks(generators, claimed);
+        }
+        n
+    }
+
+    // Generates queued chunks as they arrive until `stop_workers` is called. Intended to be run
+    // on one or more background threads.
+    pub fn run_worker(&self) {
+        loop {
+            {
+                let mut lock = self.lock.lock().unwrap();
+                while lock.queued.is_empty() && !lock.stop_workers {
+                    lock = self.signal.wait(lock).unwrap();
+                }
+                if lock.stop_workers {
+                    return;
+                }
+            }
+            self.generate_queued(WORKER_BATCH_SIZE);
+        }
+    }
+
+    // Adds or moves a focus point. Chunks within `radius` of any focus are queued and queued
+    // chunks are generated nearest-first. Chunks the focus queued which are no longer within its
+    // radius are cancelled, unless another focus or a live `GenerationRequest` wants them.
+    pub fn set_focus(&self, id: usize, position: P, radius: u32) {
+        let chunks = P::chunks_in_region(&P::expand(&position.to_cube(1), radius), self.chunk_size);
+        let mut lock = self.lock.lock().unwrap();
+        lock.enqueue(&chunks);
+        let focus = Focus {
+            position,
+            chunks: chunks.into_iter().collect(),
+        };
+        if let Some(old) = lock.foci.insert(id, focus) {
+            let left = old.chunks.difference(&lock.foci[&id].chunks).cloned().collect();
+            lock.abandon(&left);
+        }
+        self.signal.notify_all();
+    }
+
+    pub fn remove_focus(&self, id: usize) {
+        let mut lock = self.lock.lock().unwrap();
+        if let Some(focus) = lock.foci.remove(&id) {
+            lock.abandon(&focus.chunks);
+        }
+        self.signal.notify_all();
+    }
+
+    // Drops any queued chunks in the region. Chunks which are already being generated are not
+    // affected.
+    pub fn cancel(&self, r: &Region<P>) {
+        let mut lock = self.lock.lock().unwrap();
+        lock.cancel_where(|_, chunk| P::overlap_rect(r, chunk));
+        self.signal.notify_all();
+    }
+
+    pub fn stop_workers(&self) {
+        self.lock.lock().unwrap().stop_workers = true;
+        self.signal.notify_all();
+    }
+
+    fn generate_chunks(&self, mut generators: Generators<P, T, S>, chunks: Vec<Region<P>>) {
+        let stages = generators.len().max(1);
+        let widths: Vec<u32> = (0..stages).map(|i| generators.get(i).map_or(0, |generator| generator.umbra_width())).collect();
+        let needed = self.plan_stages(&widths, chunks);
+        let pinned: Vec<Region<P>> = needed.iter().flatten().cloned().collect();
+        self.lock.lock().unwrap().pin(&pinned);
+        if let Some(base) = &self.base {
+            base.maybe_generate(needed.iter().zip(&widths).flat_map(|(chunks, width)| {
+                chunks.iter().map(move |chunk| P::expand(chunk, *width))
+            }).collect());
+        }
+
+        for (stage, chunks) in needed.into_iter().enumerate() {
+            let claimed: Vec<Region<P>> = {
+                let mut lock = self.lock.lock().unwrap();
+                let claimed: Vec<Region<P>> = chunks.iter()
+                    .filter(|chunk| lock.stage(chunk, stages) == stage && !lock.advancing.contains(*chunk))
+                    .cloned()
+                    .collect();
+                lock.advancing.extend(claimed.iter().cloned());
+                claimed
+            };
+
+            // Chunks whose umbras don't overlap can be written concurrently without contending for
+            // the region lock so group them into batches of mutually independent chunks
+            let mut batches: Vec<Vec<(Region<P>, Region<P>)>> = vec![];
+            for chunk in claimed {
+                let umbra = P::expand(&chunk, widths[stage]);
+                let batch = batches.iter_mut().find(|batch| {
+                    batch.iter().all(|(_, other)| !P::overlap_rect(&umbra, other))
+                });
+                match batch {
+                    Some(batch) => batch.push((chunk, umbra)),
+                    None => batches.push(vec![(chunk, umbra)]),
+                }
+            }
+            for batch in batches {
+                let done: Vec<Region<P>> = batch.iter().map(|(chunk, _)| chunk.clone()).collect();
+                let loaded = self.generate_batch(&mut generators, stage, batch);
 
-            for p in P::points_in_region(chunk) {
-                self.map.insert_new(p, T::default());
+                let mut lock = self.lock.lock().unwrap();
+                for (chunk, loaded) in done.into_iter().zip(loaded) {
+                    lock.advancing.remove(&chunk);
+                    if loaded || stage + 1 == stages {
+                        lock.complete(chunk);
+                    } else {
+                        lock.progress.insert(chunk, stage + 1);
+                    }
+                }
+                for waker in lock.wakers.drain(..) {
+                    waker.wake();
+                }
+                self.signal.notify_all();
             }
 
+            // Some of the chunks may be going through this stage on another thread
+            let mut lock = self.lock.lock().unwrap();
+            while chunks.iter().any(|chunk| lock.stage(chunk, stages) <= stage) {
+                lock = self.signal.wait(lock).unwrap();
+            }
+        }
+        self.lock.lock().unwrap().unpin(&pinned);
+    }
+
+    // Each generator can look `umbra_width` tiles outside its chunk, so every chunk within that
+    // margin has to have been through the earlier generators first. Working back from the last
+    // generator, finds the chunks each one has to run on.
+    fn plan_stages(&self, widths: &[u32], chunks: Vec<Region<P>>) -> Vec<Vec<Region<P>>> {
+        let mut needed = vec![chunks];
+        for stage in (1..widths.len()).rev() {
+            let mut seen = HashSet::new();
+            let around = needed[0].iter()
+                .flat_map(|chunk| P::chunks_in_region(&P::expand(chunk, widths[stage]), self.chunk_size))
+                .filter(|chunk| seen.insert(chunk.clone()))
+                .collect();
+            needed.insert(0, around);
+        }
+        needed
+    }
+
+    // Returns which chunks were loaded from the store instead, those are already complete
+    #[cfg(not(feature = "rayon"))]
+    fn generate_batch(&self, generators: &mut [Box<dyn generator::Generator<P, T, S>>], stage: usize, batch: Vec<(Region<P>, Region<P>)>) -> Vec<bool> {
+        batch.into_iter()
+            .map(|(chunk, umbra)| self.generate_stage(generators.get_mut(stage), stage, &chunk, &umbra))
+            .collect()
+    }
+
+    #[cfg(feature = "rayon")]
+    fn generate_batch(&self, generators: &mut [Box<dyn generator::Generator<P, T, S>>], stage: usize, batch: Vec<(Region<P>, Region<P>)>) -> Vec<bool> {
+        // Each worker gets its own copy of the generator, so any state it accumulates while
+        // generating is discarded at the end of the batch
+        let template = Mutex::new(generators.get(stage).cloned());
+        batch.into_par_iter().map_init(
+            || template.lock().unwrap().clone(),
+            |generator, (chunk, umbra)| self.generate_stage(generator.as_mut(), stage, &chunk, &umbra),
+        ).collect()
+    }
+
+    // Runs one generator on the chunk, loading the chunk first if this is its first stage
+    fn generate_stage(&self, generator: Option<&mut Box<dyn generator::Generator<P, T, S>>>, stage: usize, chunk: &Region<P>, umbra: &Region<P>) -> bool {
+        let region_lock = self.region_lock.write_region(&RegionSet::from(umbra));
+
+        if stage == 0 {
+            self.chunk_data.lock().unwrap().entry(chunk.clone()).or_default();
+            if let Some(store) = &self.store {
+                match store.load(chunk) {
+                    Ok(Some(tiles)) => {
+                        self.tiles.insert(chunk, tiles);
+                        return true;
+                    },
+                    Ok(None) => (),
+                    Err(e) => warn!("Failed to load chunk {:?}, regenerating it: {}", chunk, e),
+                }
+            }
+
+            self.tiles.insert_default(chunk);
+        }
+
+        if let Some(generator) = generator {
+            // The generator can write to generated chunks in its umbra, those have to be saved
+            // before they're evicted like any other change and subscribers told about them
+            let generated: RegionSet<P> = {
+                let lock = self.lock.lock().unwrap();
+                P::chunks_in_region(umbra, self.chunk_size).into_iter().filter(|chunk| lock.generated.contains(chunk)).collect()
+            };
             let mut writer = WriteGuard {
-                data: &self.map,
-                region_lock,
-                region: chunk.clone(),
+                view: self.tiles.view(umbra),
+                chunk_data: self.chunk_data_in(umbra),
+                region: umbra.clone(),
+                region_lock: self.held(region_lock, true),
+                seed: seed::derive_seed(self.seed, &(stage, chunk)),
+                map: None,
+                subscribers: None,
+                changed: Changes::new(!generated.is_empty()),
             };
+            generator.generate(&mut writer, chunk, umbra);
+            // The chunks being generated are reported by `drain_dirty_regions` instead
+            let changed = writer.take_changed().intersection(&generated);
+            if !changed.is_empty() {
+                self.lock.lock().unwrap().mark_modified(&changed.chunks(self.chunk_size));
+                if let Some(subscribers) = self.subscribers() {
+                    notify(subscribers, &changed);
+                }
+            }
+        }
+        false
+    }
+
+    // Removes the tiles of every loaded chunk in the region, waiting for any guards which overlap
+    // them to be released. Unloaded chunks which were fully generated are marked as evicted, and
+    // any chunk will be generated again if requested. Chunks being generated are left alone.
+    pub fn unload(&self, r: &Region<P>) -> Vec<Region<P>> {
+        let mut unloaded = vec![];
+        for chunk in P::chunks_in_region(r, self.chunk_size) {
+            if !self.lock.lock().unwrap().is_evictable(&chunk) {
+                continue;
+            }
+            let region_lock = self.region_lock.write_region(&RegionSet::from(&chunk));
+            if self.evict_chunk(&chunk, region_lock) {
+                unloaded.push(chunk);
+            }
+        }
+        unloaded
+    }
+
+    // Evicts chunks until at most `max_chunks` remain loaded, counting the partly generated chunks
+    // around generated ones, which have never been used so go first by LRU. Chunks which are
+    // currently locked by a guard are skipped rather than waited on, and chunks being generated
+    // aren't candidates.
+    pub fn evict_to_budget(&self, max_chunks: usize, policy: EvictionPolicy) -> Vec<Region<P>> {
+        let candidates = {
+            let lock = self.lock.lock().unwrap();
+            let excess = (lock.generated.len() + lock.progress.len()).saturating_sub(max_chunks);
+            let mut candidates: Vec<Region<P>> = lock.generated.iter().chain(lock.progress.keys())
+                .filter(|chunk| lock.is_evictable(chunk))
+                .cloned()
+                .collect();
+            match policy {
+                EvictionPolicy::LeastRecentlyUsed => {
+                    candidates.sort_by_key(|chunk| lock.last_used.get(chunk).cloned().unwrap_or(0));
+                },
+                EvictionPolicy::FurthestFromFocus => {
+                    let mut keyed: Vec<(f64, u64, Region<P>)> = candidates.into_iter().map(|chunk| {
+                        (lock.distance_to_focus(&chunk), lock.last_used.get(&chunk).cloned().unwrap_or(0), chunk)
+                    }).collect();
+                    keyed.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap().then(a.1.cmp(&b.1)));
+                    candidates = keyed.into_iter().map(|(_, _, chunk)| chunk).collect();
+                },
+            }
+            candidates.truncate(excess);
+            candidates
+        };
+
+        let mut evicted = vec![];
+        for chunk in candidates {
+            if let Some(region_lock) = self.region_lock.try_write_region(&RegionSet::from(&chunk)) {
+                if self.evict_chunk(&chunk, region_lock) {
+                    evicted.push(chunk);
+                }
+            }
+        }
+        evicted
+    }
+
+    pub fn is_evicted(&self, chunk: &Region<P>) -> bool {
+        self.lock.lock().unwrap().evicted.contains(chunk)
+    }
+
+    pub fn stage(&self, chunk: &Region<P>) -> Stage {
+        let lock = self.lock.lock().unwrap();
+        if lock.generated.contains(chunk) {
+            Stage::Generated
+        } else {
+            lock.progress.get(chunk).map_or(Stage::Unloaded, |stage| Stage::Partial(*stage))
+        }
+    }
+
+    // Every loaded chunk and how far along it is
+    pub fn chunk_stages(&self) -> Vec<(Region<P>, Stage)> {
+        let lock = self.lock.lock().unwrap();
+        let generated = lock.generated.iter().map(|chunk| (chunk.clone(), Stage::Generated));
+        let partial = lock.progress.iter().map(|(chunk, stage)| (chunk.clone(), Stage::Partial(*stage)));
+        generated.chain(partial).collect()
+    }
+
+    fn evict_chunk(&self, chunk: &Region<P>, _region_lock: Guard<'_, P>) -> bool {
+        let mut lock = self.lock.lock().unwrap();
+        if !lock.is_evictable(chunk) {
+            return false;
+        }
+        if lock.progress.remove(chunk).is_some() {
+            self.tiles.remove(chunk);
+            self.chunk_data.lock().unwrap().remove(chunk);
+            return true;
+        }
+        if lock.modified.contains(chunk) {
+            // Saved without holding the lock. The region lock keeps anything from writing to the
+            // chunk meanwhile, but it can be wanted again so it's checked once more after.
+            drop(lock);
+            if let Err(e) = self.save_chunk(chunk) {
+                warn!("Failed to save chunk {:?}, keeping it loaded: {}", chunk, e);
+                return false;
+            }
+            lock = self.lock.lock().unwrap();
+            lock.modified.remove(chunk);
+            if !lock.is_evictable(chunk) {
+                return false;
+            }
+        }
+        lock.generated.remove(chunk);
+        self.tiles.remove(chunk);
+        self.chunk_data.lock().unwrap().remove(chunk);
+        lock.last_used.remove(chunk);
+        lock.evicted.insert(chunk.clone());
+        true
+    }
+
+    // Writes every modified chunk to the store
+    pub fn flush(&self) -> error::Result<()> {
+        let modified: Vec<Region<P>> = self.lock.lock().unwrap().modified.iter().cloned().collect();
+        for chunk in modified {
+            let _region_lock = self.region_lock.read_region(&RegionSet::from(&chunk));
+            // Nothing can write to it while it's read locked, so the lock isn't needed for the save
+            if self.lock.lock().unwrap().modified.contains(&chunk) {
+                self.save_chunk(&chunk)?;
+                self.lock.lock().unwrap().modified.remove(&chunk);
+            }
+        }
+        Ok(())
+    }
+
+    fn save_chunk(&self, chunk: &Region<P>) -> std::io::Result<()> {
+        if let Some(store) = &self.store {
+            let view = self.tiles.view(chunk);
+            // Safety: both callers hold a region lock on the chunk
+            let tiles: Option<Vec<&T>> = chunk.points().iter().map(|p| view.tile(p).map(|tile| unsafe { &*tile })).collect();
+            if let Some(tiles) = tiles {
+                store.save(chunk, &tiles)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Copies out every generated chunk which overlaps the region
+    pub fn snapshot(&self, r: &Region<P>) -> Snapshot<P, T> where T: Clone {
+        let chunks: Vec<Region<P>> = {
+            let lock = self.lock.lock().unwrap();
+            P::chunks_in_region(r, self.chunk_size).into_iter().filter(|chunk| lock.generated.contains(chunk)).collect()
+        };
+        let _region_lock = self.region_lock.read_region(&RegionSet::from(chunks.as_slice()));
+        // Any of them could have been evicted before the read lock was taken, none can be after
+        let (chunks, tiles) = chunks.into_iter().filter(|chunk| self.lock.lock().unwrap().generated.contains(chunk)).filter_map(|chunk| {
+            let view = self.tiles.view(&chunk);
+            // Safety: read locked above
+            let tiles: Option<Vec<T>> = chunk.points().iter().map(|p| view.tile(p).map(|tile| unsafe { (*tile).clone() })).collect();
+            tiles.map(|tiles| (chunk, tiles))
+        }).unzip();
+        Snapshot {
+            chunk_size: self.chunk_size,
+            chunks,
+            tiles,
+        }
+    }
 
-            for generator in &mut lock.generators {
-                generator.generate(&mut writer, chunk, &umbra);
+    // Writes the snapshot's chunks back into the map, replacing whatever was there. Chunks which
+    // were not generated when the snapshot was taken are left alone. Waits for any of the chunks
+    // which are being generated to finish first, so no generator runs over the restored tiles.
+    pub fn restore(&self, snapshot: Snapshot<P, T>) {
+        assert_eq!(snapshot.chunk_size, self.chunk_size, "Snapshot was taken from a map with a different chunk size");
+        {
+            let mut lock = self.lock.lock().unwrap();
+            while snapshot.chunks.iter().any(|chunk| lock.in_progress.contains(chunk) || lock.advancing.contains(chunk)) {
+                lock = self.signal.wait(lock).unwrap();
             }
+            for chunk in &snapshot.chunks {
+                lock.in_progress.insert(chunk.clone());
+                lock.advancing.insert(chunk.clone());
+            }
+        }
+        let _region_lock = self.region_lock.write_region(&RegionSet::from(snapshot.chunks.as_slice()));
+        let mut lock = self.lock.lock().unwrap();
+        for (chunk, tiles) in snapshot.chunks.iter().zip(snapshot.tiles) {
+            self.tiles.insert(chunk, tiles);
+            self.chunk_data.lock().unwrap().entry(chunk.clone()).or_default();
+            lock.in_progress.remove(chunk);
+            lock.advancing.remove(chunk);
+            lock.queued.retain(|other| other != chunk);
+            lock.evicted.remove(chunk);
+            lock.progress.remove(chunk);
+            lock.generated.insert(chunk.clone());
+            lock.resolve(chunk);
+            lock.modified.insert(chunk.clone());
+            lock.dirty_chunks.push(chunk.clone());
+        }
+        let restored = RegionSet::from(snapshot.chunks.as_slice());
+        lock.touch(snapshot.chunks);
+        for waker in lock.wakers.drain(..) {
+            waker.wake();
         }
-        lock.generated.extend(to_generate);
+        self.signal.notify_all();
+        drop(lock);
+        notify(&self.subscribers, &restored);
     }
 
-    pub fn drain_dirty_regions(&self) -> Vec<[P; 2]> {
+    pub fn drain_dirty_regions(&self) -> RegionSet<P> {
         let mut lock = self.lock.lock().unwrap();
         lock.dirty_chunks.drain(..).collect()
     }
 
-    pub fn get(&self, p: &P) -> TileReadGuard<'_, P, T> {
+    // Every chunk which is generated and currently loaded
+    pub fn generated_regions(&self) -> RegionSet<P> {
+        let lock = self.lock.lock().unwrap();
+        lock.generated.iter().cloned().collect()
+    }
+
+    // Panics if the tile isn't loaded, see `try_get`
+    pub fn get(&self, p: &P) -> TileReadGuard<'_, P, T, S> {
+        self.try_get(p).unwrap_or_else(|e| panic!("Can't get tile {:?}: {}", p, e))
+    }
+
+    pub fn get_mut(&self, p: &P) -> TileWriteGuard<'_, P, T, S> {
+        self.try_get_mut(p).unwrap_or_else(|e| panic!("Can't get tile {:?}: {}", p, e))
+    }
+
+    pub fn try_get(&self, p: &P) -> error::Result<TileReadGuard<'_, P, T, S>> {
         let r = p.to_cube(1);
-        let lock = self.region_lock.read_region(&[r]);
-        TileReadGuard {
-            data: self.map.get(p).unwrap(),
-            region_lock: lock,
+        self.lock.lock().unwrap().touch(P::chunks_in_region(&r, self.chunk_size));
+        let region_lock = self.region_lock.read_region(&RegionSet::from(&r));
+        let view = self.tiles.view(&r);
+        match view.tile(p) {
+            Some(tile) => Ok(TileReadGuard {
+                tile,
+                _view: view,
+                region_lock,
+            }),
+            None => Err(self.missing(p)),
         }
     }
 
-    pub fn get_mut(&self, p: &P) -> TileWriteGuard<'_, P, T> {
+    pub fn try_get_mut(&self, p: &P) -> error::Result<TileWriteGuard<'_, P, T, S>> {
         let r = p.to_cube(1);
-        let lock = self.region_lock.write_region(&[r]);
-        TileWriteGuard {
-            data: self.map.get_mut(p).unwrap(),
-            region_lock: lock,
+        let region_lock = self.region_lock.write_region(&RegionSet::from(&r));
+        let view = self.tiles.view(&r);
+        let tile = match view.tile(p) {
+            Some(tile) => tile,
+            None => return Err(self.missing(p)),
+        };
+        self.lock.lock().unwrap().touch(P::chunks_in_region(&r, self.chunk_size));
+        Ok(TileWriteGuard {
+            tile,
+            _view: view,
+            point: p.clone(),
+            map: self,
+            subscribers: self.subscribers(),
+            changed: false,
+            region_lock,
+        })
+    }
+
+    // Generates the chunk containing the tile first if it isn't loaded
+    pub fn get_or_generate(&self, p: &P) -> error::Result<TileReadGuard<'_, P, T, S>> {
+        self.maybe_generate(p.to_cube(1));
+        self.try_get(p)
+    }
+
+    fn missing(&self, p: &P) -> Error {
+        let chunk = p.chunk_index(self.chunk_size).0.to_cube(self.chunk_size);
+        if self.lock.lock().unwrap().evicted.contains(&chunk) {
+            Error::Evicted
+        } else {
+            Error::NotGenerated
         }
     }
 
-    pub fn region(&self, r: &[P; 2]) -> ReadGuard<'_, P, T> {
-        let lock = self.region_lock.read_region(&[r.clone()]);
-        ReadGuard {
-            data: &self.map,
-            region_lock: lock,
+    pub fn region(&self, r: &Region<P>) -> ReadGuard<'_, P, T, S> {
+        self.lock_region(r, None).unwrap()
+    }
+
+    pub fn region_mut(&self, r: &Region<P>) -> WriteGuard<'_, P, T, S> {
+        self.lock_region_mut(r, None).unwrap()
+    }
+
+    // Like `region` but gives up with `Error::LockTimeout` if an overlapping `region_mut` guard is
+    // held for longer than the timeout
+    pub fn try_region(&self, r: &Region<P>, timeout: Duration) -> error::Result<ReadGuard<'_, P, T, S>> {
+        self.lock_region(r, Some(timeout))
+    }
+
+    pub fn try_region_mut(&self, r: &Region<P>, timeout: Duration) -> error::Result<WriteGuard<'_, P, T, S>> {
+        self.lock_region_mut(r, Some(timeout))
+    }
+
+    fn lock_region(&self, r: &Region<P>, timeout: Option<Duration>) -> error::Result<ReadGuard<'_, P, T, S>> {
+        self.lock.lock().unwrap().touch(P::chunks_in_region(r, self.chunk_size));
+        let region_lock = self.region_lock.lock_region_timeout(&RegionSet::from(r), false, timeout)
+            .ok_or(Error::LockTimeout)?;
+        Ok(ReadGuard {
+            view: self.tiles.view(r),
+            chunk_data: self.chunk_data_in(r),
             region: r.clone(),
-        }
+            region_lock: self.held(region_lock, false),
+        })
+    }
+
+    fn lock_region_mut(&self, r: &Region<P>, timeout: Option<Duration>) -> error::Result<WriteGuard<'_, P, T, S>> {
+        let region_lock = self.region_lock.lock_region_timeout(&RegionSet::from(r), true, timeout)
+            .ok_or(Error::LockTimeout)?;
+        self.lock.lock().unwrap().touch(P::chunks_in_region(r, self.chunk_size));
+        Ok(WriteGuard {
+            view: self.tiles.view(r),
+            chunk_data: self.chunk_data_in(r),
+            region: r.clone(),
+            region_lock: self.held(region_lock, true),
+            seed: seed::derive_seed(self.seed, r),
+            map: Some(self),
+            subscribers: self.subscribers(),
+            changed: Changes::new(true),
+        })
     }
 
-    pub fn region_mut(&self, r: &[P; 2]) -> WriteGuard<'_, P, T> {
-        let lock = self.region_lock.read_region(&[r.clone()]);
-        WriteGuard {
-            data: &self.map,
-            region_lock: lock,
+    // Receives the parts of the region written through `get_mut` and `region_mut` guards as each
+    // guard is dropped, by generators into already generated chunks around the one they generate,
+    // and by `restore`. Guards only report the tiles they handed out mutably, whether or not they
+    // were changed, and guards taken before subscribing report nothing. Drop the receiver to
+    // unsubscribe.
+    pub fn subscribe(&self, r: &Region<P>) -> Receiver<RegionSet<P>> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(Subscriber {
             region: r.clone(),
+            sender,
+        });
+        receiver
+    }
+
+    // Saves guards from tracking changes when nobody is listening
+    fn subscribers(&self) -> Option<&Mutex<Vec<Subscriber<P>>>> {
+        if self.subscribers.lock().unwrap().is_empty() {
+            None
+        } else {
+            Some(&self.subscribers)
+        }
+    }
+
+    // Every value of type `M` attached to a loaded chunk overlapping the region, see
+    // `WriteGuard::set_chunk_data`. Use a guard's `chunk_data` to borrow them instead.
+    pub fn chunk_data<M: Any + Send + Sync + Clone>(&self, r: &Region<P>) -> Vec<(Region<P>, M)> {
+        let _region_lock = self.region_lock.read_region(&RegionSet::from(r));
+        let data = self.chunk_data_in(r);
+        data.iter().filter_map(|(chunk, data)| {
+            // Safety: read locked above
+            let value = unsafe { &*data.0.get() }.get(&TypeId::of::<M>())?.downcast_ref::<M>()?;
+            Some((chunk.clone(), value.clone()))
+        }).collect()
+    }
+
+    fn chunk_data_in(&self, r: &Region<P>) -> HashMap<Region<P>, Arc<ChunkData>> {
+        let loaded = self.chunk_data.lock().unwrap();
+        P::chunks_in_region(r, self.chunk_size).into_iter().filter_map(|chunk| {
+            let data = loaded.get(&chunk)?.clone();
+            Some((chunk, data))
+        }).collect()
+    }
+
+    fn held<'a>(&'a self, guard: Guard<'a, P>, is_write: bool) -> Arc<Held<'a, P>> {
+        Arc::new(Held {
+            lock: &self.region_lock,
+            guard,
+            writer: if is_write { Some(self.id()) } else { None },
+        })
+    }
+
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    // This map's tiles under a lock taken on one of its layers
+    fn layer_guard<'a>(&self, held: &Arc<Held<'a, P>>, region: &Region<P>) -> ReadGuard<'a, P, T, S> {
+        assert!(std::ptr::eq(held.lock, &*self.region_lock), "Only layers made with `Map::new_layer` share a region lock");
+        assert!(held.writer != Some(self.id()), "The guard's own map is locked for writing");
+        self.lock.lock().unwrap().touch(P::chunks_in_region(region, self.chunk_size));
+        ReadGuard {
+            view: self.tiles.view(region),
+            chunk_data: self.chunk_data_in(region),
+            region: region.clone(),
+            region_lock: held.clone(),
+        }
+    }
+}
+
+// A region lock held by a guard, and by the guards it hands out for other layers
+struct Held<'a, P: Point> {
+    lock: &'a RegionLock<P>,
+    #[allow(dead_code)] // Never used because it's just here to hold the inner lock open while this object is in scope
+    guard: Guard<'a, P>,
+    // The map the lock was taken to write, whose tiles no other guard may hand out
+    writer: Option<usize>,
+}
+
+// An owned copy of some of a map's chunks. Tiles are stored per chunk in the order given by
+// `Point::points_in_region`.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Snapshot<P, T> {
+    pub chunk_size: u32,
+    pub chunks: Vec<Region<P>>,
+    pub tiles: Vec<Vec<T>>,
+}
+
+impl<P: Point, T: PartialEq> PartialEq for Snapshot<P, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.chunk_size == other.chunk_size && self.chunks == other.chunks && self.tiles == other.tiles
+    }
+}
+
+// How far along generation a chunk is. Chunks around a requested chunk are taken through the
+// generators which the requested chunk's later generators need to see in their umbra, so they can
+// be loaded but only partly generated.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Stage {
+    Unloaded,
+    // The number of generators which have run on the chunk
+    Partial(usize),
+    Generated,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    LeastRecentlyUsed,
+    FurthestFromFocus,
+}
+
+pub struct GenerationRequest<'a, P, T, S = ChunkedStorage<P, T>> where P: Point, S: Storage<P, T> {
+    map: &'a Map<P, T, S>,
+    id: usize,
+    chunks: Vec<Region<P>>,
+}
+
+impl<'a, P: Point, T, S: Storage<P, T>> GenerationRequest<'a, P, T, S> {
+    pub fn chunks(&self) -> &[Region<P>] {
+        &self.chunks
+    }
+
+    // True if some chunk in the request was cancelled before it could be generated
+    pub fn is_cancelled(&self) -> bool {
+        let lock = self.map.lock.lock().unwrap();
+        self.pending(&lock).cancelled && !self.is_ready_locked(&lock)
+    }
+
+    fn pending<'l>(&self, lock: &'l Lock<P, T, S>) -> &'l Pending<P> {
+        &lock.requests[&self.id]
+    }
+
+    fn is_ready_locked(&self, lock: &Lock<P, T, S>) -> bool {
+        self.pending(lock).chunks.is_empty()
+    }
+
+    // Chunks which have been generated since the request was made, or already were. They may
+    // have been evicted since.
+    pub fn ready_chunks(&self) -> Vec<Region<P>> {
+        let lock = self.map.lock.lock().unwrap();
+        let pending = self.pending(&lock);
+        self.chunks.iter().filter(|chunk| !pending.chunks.contains(*chunk)).cloned().collect()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        let lock = self.map.lock.lock().unwrap();
+        self.is_ready_locked(&lock)
+    }
+
+    // Blocks until every chunk has been generated, returning false if any were cancelled instead
+    pub fn wait(&self) -> bool {
+        let mut lock = self.map.lock.lock().unwrap();
+        loop {
+            if self.is_ready_locked(&lock) {
+                return true;
+            }
+            if self.pending(&lock).cancelled {
+                return false;
+            }
+            lock = self.map.signal.wait(lock).unwrap();
+        }
+    }
+}
+
+impl<'a, P: Point, T, S: Storage<P, T>> Drop for GenerationRequest<'a, P, T, S> {
+    fn drop(&mut self) {
+        let mut lock = self.map.lock.lock().unwrap();
+        if let Some(request) = lock.requests.remove(&self.id) {
+            lock.abandon(&request.chunks);
         }
     }
 }
 
-pub type LightTileReadGuard<'a, P, T> = chashmap::ReadGuard<'a, P, T>;
-pub type LightTileWriteGuard<'a, P, T> = chashmap::WriteGuard<'a, P, T>;
+impl<'a, P: Point, T, S: Storage<P, T>> Future for GenerationRequest<'a, P, T, S> {
+    type Output = bool;
 
-pub struct TileReadGuard<'a, P, T> where P: Point {
-    data: chashmap::ReadGuard<'a, P, T>,
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<bool> {
+        let mut lock = self.map.lock.lock().unwrap();
+        if self.is_ready_locked(&lock) {
+            Poll::Ready(true)
+        } else if self.pending(&lock).cancelled {
+            Poll::Ready(false)
+        } else {
+            lock.wakers.push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+pub struct TileReadGuard<'a, P, T, S = ChunkedStorage<P, T>> where P: Point, S: Storage<P, T> {
+    tile: *const T,
+    // Keeps the tile alive
+    _view: S::View,
     #[allow(dead_code)]
     region_lock: Guard<'a, P>,
 }
 
-impl<'a, P: Point, T> std::ops::Deref for TileReadGuard<'a, P, T> {
+impl<'a, P: Point, T, S: Storage<P, T>> std::ops::Deref for TileReadGuard<'a, P, T, S> {
     type Target = T;
     fn deref(&self) -> &T {
-        &self.data
+        // Safety: the tile is read locked for as long as the guard lives
+        unsafe { &*self.tile }
     }
 }
 
-pub struct TileWriteGuard<'a, P, T> where P: Point {
-    data: chashmap::WriteGuard<'a, P, T>,
+pub struct TileWriteGuard<'a, P, T, S = ChunkedStorage<P, T>> where P: Point, S: Storage<P, T> {
+    tile: *mut T,
+    _view: S::View,
+    point: P,
+    map: &'a Map<P, T, S>,
+    subscribers: Option<&'a Mutex<Vec<Subscriber<P>>>>,
+    changed: bool,
     #[allow(dead_code)]
     region_lock: Guard<'a, P>,
 }
 
-impl<'a, P: Point, T> std::ops::Deref for TileWriteGuard<'a, P, T> {
+impl<'a, P: Point, T, S: Storage<P, T>> std::ops::Deref for TileWriteGuard<'a, P, T, S> {
     type Target = T;
     fn deref(&self) -> &T {
-        &self.data
+        // Safety: the tile is write locked for as long as the guard lives
+        unsafe { &*self.tile }
     }
 }
 
-impl<'a, P: Point, T> std::ops::DerefMut for TileWriteGuard<'a, P, T> {
+impl<'a, P: Point, T, S: Storage<P, T>> std::ops::DerefMut for TileWriteGuard<'a, P, T, S> {
     fn deref_mut(&mut self) -> &mut T {
-        &mut self.data
+        self.changed = true;
+        // Safety: as above, and borrowing the guard mutably keeps this the only reference
+        unsafe { &mut *self.tile }
     }
 }
 
-pub struct ReadGuard<'a, P, T> where P: Point {
-    data: &'a CHashMap<P, T>,
-    #[allow(dead_code)] // Never used because it's just here to hold the inner lock open while this object is in scope
-    region_lock: Guard<'a, P>,
-    region: [P; 2],
+impl<'a, P: Point, T, S: Storage<P, T>> Drop for TileWriteGuard<'a, P, T, S> {
+    fn drop(&mut self) {
+        if !self.changed {
+            return;
+        }
+        let chunks = P::chunks_in_region(&self.point.to_cube(1), self.map.chunk_size);
+        self.map.lock.lock().unwrap().mark_modified(&chunks);
+        if let Some(subscribers) = self.subscribers {
+            notify(subscribers, &RegionSet::from(self.point.to_cube(1)));
+        }
+    }
 }
 
-impl<'a, P: Point, T> ReadGuard<'a, P, T> {
-    pub fn get(&self, p: &P) -> Result<LightTileReadGuard<'a, P, T>, ()> {
-        if p.contained(&self.region) {
-            Ok(self.data.get(p).unwrap())
-        } else {
-            Err(())
+// Guards work on the tiles directly, the region lock they hold is what keeps them from racing
+// with other guards
+pub struct ReadGuard<'a, P, T, S = ChunkedStorage<P, T>> where P: Point, S: Storage<P, T> {
+    view: S::View,
+    chunk_data: HashMap<Region<P>, Arc<ChunkData>>,
+    region: Region<P>,
+    region_lock: Arc<Held<'a, P>>,
+}
+
+impl<'a, P: Point, T, S: Storage<P, T>> ReadGuard<'a, P, T, S> {
+    pub fn get(&self, p: &P) -> error::Result<&T> {
+        tile(&self.view, &self.region, p).map(|tile| unsafe { &*tile })
+    }
+
+    // Every loaded tile in the guarded region
+    pub fn iter(&self) -> impl Iterator<Item=(P, &T)> + '_ {
+        self.iter_region(&self.region)
+    }
+
+    // Every loaded tile in the part of `sub` inside the guarded region
+    pub fn iter_region(&self, sub: &Region<P>) -> impl Iterator<Item=(P, &T)> + '_ {
+        self.view.tiles(sub.clone()).map(|(p, tile)| (p, unsafe { &*tile }))
+    }
+
+    // Another layer's tiles in the guarded region, read under this guard's lock, which is held
+    // until both guards are dropped. Panics unless the map was made with `Map::new_layer` from
+    // this guard's map, from a map it was made from, or from the same map.
+    pub fn layer<U: Default + Send + Sync, S2: Storage<P, U>>(&self, layer: &Map<P, U, S2>) -> ReadGuard<'a, P, U, S2> {
+        layer.layer_guard(&self.region_lock, &self.region)
+    }
+
+    // The chunk's value of type `M`, if it has one. The chunk only has to overlap the guarded
+    // region.
+    pub fn chunk_data<M: Any + Send + Sync>(&self, chunk: &Region<P>) -> error::Result<Option<&M>> {
+        let data = chunk_data(&self.chunk_data, &self.region, chunk, false)?;
+        Ok(unsafe { &*data }.get(&TypeId::of::<M>()).and_then(|value| value.downcast_ref()))
+    }
+}
+
+fn tile<P: Point, T>(view: &impl View<P, T>, region: &Region<P>, p: &P) -> error::Result<*mut T> {
+    if p.contained(region) {
+        view.tile(p).ok_or(Error::NotGenerated)
+    } else {
+        Err(Error::OutOfRegion)
+    }
+}
+
+// Writing a chunk's data needs the whole chunk locked, reading it only needs some of it
+fn chunk_data<P: Point>(data: &HashMap<Region<P>, Arc<ChunkData>>, region: &Region<P>, chunk: &Region<P>, is_write: bool) -> error::Result<*mut HashMap<TypeId, Box<dyn Any + Send + Sync>>> {
+    let covered = if is_write {
+        chunk.difference(region).is_empty()
+    } else {
+        chunk.overlaps(region)
+    };
+    if covered {
+        data.get(chunk).map(|data| data.0.get()).ok_or(Error::NotGenerated)
+    } else {
+        Err(Error::OutOfRegion)
+    }
+}
+
+pub struct WriteGuard<'a, P, T, S = ChunkedStorage<P, T>> where P: Point, S: Storage<P, T> {
+    view: S::View,
+    chunk_data: HashMap<Region<P>, Arc<ChunkData>>,
+    region: Region<P>,
+    region_lock: Arc<Held<'a, P>>,
+    seed: u64,
+    // The chunks written to are marked modified on drop, generators mark their own
+    map: Option<&'a Map<P, T, S>>,
+    subscribers: Option<&'a Mutex<Vec<Subscriber<P>>>>,
+    // What's been handed out mutably
+    changed: Changes<P>,
+}
+
+// Kept as they come and only merged into a `RegionSet` once the guard is done with, so each write
+// doesn't have to be checked against all the ones before it
+struct Changes<P> {
+    // Nothing is recorded unless something needs to know
+    enabled: bool,
+    regions: Vec<Region<P>>,
+    points: HashSet<P>,
+}
+
+impl<P: Point> Changes<P> {
+    fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            regions: vec![],
+            points: HashSet::new(),
         }
     }
+
+    fn take(&mut self) -> RegionSet<P> {
+        let regions = std::mem::take(&mut self.regions);
+        let points = std::mem::take(&mut self.points);
+        let mut changed: RegionSet<P> = regions.into_iter().collect();
+        let bulk = changed.clone();
+        for p in points {
+            if !bulk.contains(&p) {
+                changed.push_disjoint(p.to_cube(1));
+            }
+        }
+        changed
+    }
 }
 
-pub struct WriteGuard<'a, P, T> where P: Point {
-    data: &'a CHashMap<P, T>,
-    #[allow(dead_code)] // Never used because it's just here to hold the inner lock open while this object is in scope
-    region_lock: Guard<'a, P>,
-    region: [P; 2],
+impl<'a, P: Point, T, S: Storage<P, T>> WriteGuard<'a, P, T, S> {
+    // Deterministic for a given world seed, generator and chunk, regardless of generation order
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn get(&self, p: &P) -> error::Result<&T> {
+        tile(&self.view, &self.region, p).map(|tile| unsafe { &*tile })
+    }
+
+    pub fn get_mut(&mut self, p: &P) -> error::Result<&mut T> {
+        let tile = tile(&self.view, &self.region, p)?;
+        if self.changed.enabled {
+            self.changed.points.insert(p.clone());
+        }
+        Ok(unsafe { &mut *tile })
+    }
+
+    fn take_changed(&mut self) -> RegionSet<P> {
+        self.changed.take()
+    }
+
+    // Lets a generator read the layers it's built on while it writes this one, see
+    // `ReadGuard::layer`. The guard's own map can't be read this way.
+    pub fn layer<U: Default + Send + Sync, S2: Storage<P, U>>(&self, layer: &Map<P, U, S2>) -> ReadGuard<'a, P, U, S2> {
+        layer.layer_guard(&self.region_lock, &self.region)
+    }
+
+    pub fn chunk_data<M: Any + Send + Sync>(&self, chunk: &Region<P>) -> error::Result<Option<&M>> {
+        let data = chunk_data(&self.chunk_data, &self.region, chunk, false)?;
+        Ok(unsafe { &*data }.get(&TypeId::of::<M>()).and_then(|value| value.downcast_ref()))
+    }
+
+    // Unlike reading, the whole chunk has to be inside the guarded region. A generator can always
+    // write to its core region's chunk.
+    pub fn chunk_data_mut<M: Any + Send + Sync>(&mut self, chunk: &Region<P>) -> error::Result<Option<&mut M>> {
+        let data = chunk_data(&self.chunk_data, &self.region, chunk, true)?;
+        Ok(unsafe { &mut *data }.get_mut(&TypeId::of::<M>()).and_then(|value| value.downcast_mut()))
+    }
+
+    // Attaches a value to the chunk, replacing any value of the same type. Kept until the chunk is
+    // evicted, and not saved to the map's store or included in snapshots.
+    pub fn set_chunk_data<M: Any + Send + Sync>(&mut self, chunk: &Region<P>, value: M) -> error::Result<Option<M>> {
+        let data = chunk_data(&self.chunk_data, &self.region, chunk, true)?;
+        let old = unsafe { &mut *data }.insert(TypeId::of::<M>(), Box::new(value));
+        Ok(old.and_then(|old| old.downcast().ok()).map(|old| *old))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(P, &T)> + '_ {
+        self.iter_region(&self.region)
+    }
+
+    pub fn iter_region(&self, sub: &Region<P>) -> impl Iterator<Item=(P, &T)> + '_ {
+        self.view.tiles(sub.clone()).map(|(p, tile)| (p, unsafe { &*tile }))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item=(P, &mut T)> + '_ {
+        let region = self.region.clone();
+        self.iter_region_mut(&region)
+    }
+
+    // Each tile is visited once so the references don't alias
+    pub fn iter_region_mut(&mut self, sub: &Region<P>) -> impl Iterator<Item=(P, &mut T)> + '_ {
+        if self.changed.enabled {
+            let changed = RegionSet::from(sub).intersection(&RegionSet::from(&self.region));
+            self.changed.regions.extend_from_slice(changed.regions());
+        }
+        self.view.tiles(sub.clone()).map(|(p, tile)| (p, unsafe { &mut *tile }))
+    }
+
+    pub fn for_each_mut(&mut self, sub: &Region<P>, mut f: impl FnMut(&P, &mut T)) {
+        for (p, tile) in self.iter_region_mut(sub) {
+            f(&p, tile);
+        }
+    }
+
+    // Like `for_each_mut` but spread over the rayon thread pool, so tiles are visited in no
+    // particular order
+    #[cfg(feature = "rayon")]
+    pub fn par_for_each_mut(&mut self, sub: &Region<P>, f: impl Fn(&P, &mut T) + Sync + Send) where T: Send + Sync {
+        self.iter_region_mut(sub).collect::<Vec<_>>().into_par_iter().for_each(|(p, tile)| f(&p, tile));
+    }
 }
 
-impl<'a, P: Point, T> WriteGuard<'a, P, T> {
-    pub fn get(&self, p: &P) -> Result<LightTileReadGuard<'a, P, T>, ()> {
-        if p.contained(&self.region) {
-            Ok(self.data.get(p).unwrap())
-        } else {
-            Err(())
+impl<'a, P: Point, T, S: Storage<P, T>> Drop for WriteGuard<'a, P, T, S> {
+    fn drop(&mut self) {
+        let changed = self.take_changed();
+        if changed.is_empty() {
+            return;
+        }
+        if let Some(map) = self.map {
+            map.lock.lock().unwrap().mark_modified(&changed.chunks(map.chunk_size));
+        }
+        if let Some(subscribers) = self.subscribers {
+            notify(subscribers, &changed);
         }
     }
+}
 
-    pub fn get_mut(&mut self, p: &P) -> Result<LightTileWriteGuard<'a, P, T>, ()> {
-        if p.contained(&self.region) {
-            Ok(self.data.get_mut(p).unwrap())
-        } else {
-            Err(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::Generator;
+    use crate::storage::HashStorage;
+
+    #[derive(Clone)]
+    struct Fill;
+
+    impl<S: Storage<[i32; 2], u32>> Generator<[i32; 2], u32, S> for Fill {
+        fn generate(&mut self, chunk: &mut WriteGuard<'_, [i32; 2], u32, S>, core_region: &Region<[i32; 2]>, _umbra: &Region<[i32; 2]>) {
+            for p in <[i32; 2] as Point>::points_in_region(core_region) {
+                *chunk.get_mut(&p).unwrap() += 1;
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    struct Column;
+
+    impl Generator<[i32; 2], (i32, i32)> for Column {
+        fn generate(&mut self, chunk: &mut WriteGuard<'_, [i32; 2], (i32, i32)>, core_region: &Region<[i32; 2]>, _umbra: &Region<[i32; 2]>) {
+            for p in core_region.points() {
+                chunk.get_mut(&p).unwrap().0 = p[0];
+            }
+        }
+
+        fn umbra_width(&self) -> u32 {
+            0
+        }
+    }
+
+    // Sums the columns of the 7 tiles around each tile on its row, so reads 3 tiles into the
+    // neighbouring chunks
+    #[derive(Clone)]
+    struct RowSum;
+
+    impl Generator<[i32; 2], (i32, i32)> for RowSum {
+        fn generate(&mut self, chunk: &mut WriteGuard<'_, [i32; 2], (i32, i32)>, core_region: &Region<[i32; 2]>, _umbra: &Region<[i32; 2]>) {
+            for p in core_region.points() {
+                let sum = (-3..=3).map(|dx| chunk.get(&[p[0] + dx, p[1]]).unwrap().0).sum();
+                chunk.get_mut(&p).unwrap().1 = sum;
+            }
+        }
+
+        fn umbra_width(&self) -> u32 {
+            3
+        }
+    }
+
+    #[test]
+    fn generators_see_their_margin() {
+        let map: Map<[i32; 2], (i32, i32)> = Map::new(vec![Box::new(Column), Box::new(RowSum)], 8, 0);
+        map.maybe_generate(Region::new([0, 0], [8, 8]));
+        assert_eq!(map.generated_regions(), Region::new([0, 0], [8, 8]).into());
+        assert_eq!(map.lock.lock().unwrap().progress.get(&Region::new([-8, 0], [0, 8])), Some(&1));
+
+        map.maybe_generate(Region::new([-8, -8], [16, 16]));
+        let r = Region::new([-8, -8], [16, 16]);
+        let region = map.region(&r);
+        for p in r.points() {
+            assert_eq!(*region.get(&p).unwrap(), (p[0], p[0] * 7));
         }
     }
+
+    #[test]
+    fn neighbours_finish_earlier_stages() {
+        let map: Map<[i32; 2], u32> = Map::new(vec![Box::new(Fill), Box::new(Fill), Box::new(Fill)], 8, 0);
+        map.maybe_generate(Region::new([0, 0], [8, 8]));
+
+        assert_eq!(map.stage(&Region::new([0, 0], [8, 8])), Stage::Generated);
+        assert_eq!(map.stage(&Region::new([8, 8], [16, 16])), Stage::Partial(2));
+        assert_eq!(map.stage(&Region::new([-16, 0], [-8, 8])), Stage::Partial(1));
+        assert_eq!(map.stage(&Region::new([24, 0], [32, 8])), Stage::Unloaded);
+        assert_eq!(map.chunk_stages().len(), 25);
+        assert_eq!(*map.get(&[7, 7]), 3);
+        assert_eq!(*map.get(&[8, 8]), 2);
+        assert_eq!(*map.get(&[-9, 0]), 1);
+
+        map.maybe_generate(Region::new([8, 8], [16, 16]));
+        assert_eq!(*map.get(&[8, 8]), 3);
+        assert_eq!(map.stage(&Region::new([16, 16], [24, 24])), Stage::Partial(2));
+    }
+
+    #[test]
+    fn access_errors() {
+        let map: Map<[i32; 2], u32> = Map::new(vec![Box::new(Fill)], 8, 0);
+        assert!(matches!(map.try_get(&[3, 3]), Err(Error::NotGenerated)));
+        assert_eq!(*map.get_or_generate(&[3, 3]).unwrap(), 1);
+        assert!(matches!(map.region(&Region::new([0, 0], [8, 8])).get(&[8, 8]), Err(Error::OutOfRegion)));
+
+        {
+            let _writer = map.region_mut(&Region::new([0, 0], [4, 4]));
+            assert!(matches!(map.try_region(&Region::new([2, 2], [6, 6]), Duration::from_millis(10)), Err(Error::LockTimeout)));
+            assert!(map.try_region(&Region::new([4, 4], [6, 6]), Duration::from_millis(10)).is_ok());
+        }
+
+        map.unload(&Region::new([0, 0], [8, 8]));
+        assert!(matches!(map.try_get_mut(&[3, 3]), Err(Error::Evicted)));
+    }
+
+    #[test]
+    fn guard_iterators() {
+        let map: Map<[i32; 2], u32> = Map::new(vec![Box::new(Fill)], 8, 0);
+        map.maybe_generate(Region::new([0, 0], [8, 8]));
+        let r = Region::new([4, 4], [12, 12]);
+        {
+            let mut region = map.region_mut(&r);
+            assert_eq!(region.iter().count(), 16);
+            for (_, tile) in region.iter_region_mut(&Region::new([6, 6], [16, 16])) {
+                *tile += 1;
+            }
+            region.for_each_mut(&Region::new([0, 0], [5, 5]), |p, tile| *tile += p[0] as u32);
+        }
+        let region = map.region(&Region::new([0, 0], [8, 8]));
+        let total: u32 = region.iter().map(|(_, tile)| *tile).sum();
+        assert_eq!(total, 64 + 4 + 4);
+        assert_eq!(*region.iter_region(&Region::new([7, 7], [9, 9])).next().unwrap().1, 2);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_for_each() {
+        let map: Map<[i32; 2], u32> = Map::new(vec![Box::new(Fill)], 8, 0);
+        map.maybe_generate(Region::new([0, 0], [16, 16]));
+        let r = Region::new([0, 0], [16, 16]);
+        map.region_mut(&r).par_for_each_mut(&r, |p, tile| *tile = p[0] as u32);
+        assert_eq!(map.region(&r).iter().map(|(_, tile)| *tile).sum::<u32>(), 16 * (0..16).sum::<u32>());
+    }
+
+    #[test]
+    fn generates_each_chunk_once() {
+        let map: Map<[i32; 2], u32> = Map::new(vec![Box::new(Fill)], 8, 0);
+        map.maybe_generate(Region::new([-20, -20], [40, 40]));
+        map.maybe_generate(Region::new([0, -20], [60, 40]));
+
+        let r = Region::new([-24, -24], [64, 40]);
+        let region = map.region(&r);
+        for p in <[i32; 2] as Point>::points_in_region(&r) {
+            assert_eq!(*region.get(&p).unwrap(), 1);
+        }
+        let dirty = map.drain_dirty_regions();
+        assert_eq!(dirty.area(), 88 * 8 * 8);
+        assert!(dirty.contains(&[-24, -24]) && dirty.contains(&[63, 39]));
+    }
+
+    #[test]
+    fn generate_what_is_missing() {
+        let map: Map<[i32; 2], u32> = Map::new(vec![Box::new(Fill)], 8, 0);
+        map.maybe_generate(Region::new([0, 0], [16, 16]));
+        map.drain_dirty_regions();
+
+        let view = RegionSet::from(Region::new([0, 0], [32, 16]));
+        let missing = view.difference(&map.generated_regions());
+        assert_eq!(missing, Region::new([16, 0], [32, 16]).into());
+        map.maybe_generate(missing);
+        assert_eq!(map.drain_dirty_regions().area(), 16 * 16);
+        assert_eq!(*map.get(&[31, 15]), 1);
+    }
+
+    #[test]
+    fn background_generation() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let map: Arc<Map<[i32; 2], u32>> = Arc::new(Map::new(vec![Box::new(Fill)], 8, 0));
+        let request = map.request(Region::new([0, 0], [32, 32]));
+        assert_eq!(request.chunks().len(), 16);
+        assert!(!request.is_ready());
+
+        assert_eq!(map.generate_queued(1), 1);
+        assert_eq!(request.ready_chunks(), vec![Region::new([0, 0], [8, 8])]);
+
+        let worker = {
+            let map = map.clone();
+            thread::spawn(move || map.run_worker())
+        };
+        assert!(request.wait());
+        assert!(request.is_ready());
+        assert_eq!(*map.get(&[31, 31]), 1);
+        assert_eq!(map.drain_dirty_regions(), Region::new([0, 0], [32, 32]).into());
+
+        map.stop_workers();
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn requests_outlive_eviction() {
+        let map: Map<[i32; 2], u32> = Map::new(vec![Box::new(Fill)], 8, 0);
+        let request = map.request(Region::new([0, 0], [16, 8]));
+        assert_eq!(map.generate_queued(1), 1);
+        assert_eq!(map.unload(&Region::new([0, 0], [8, 8])).len(), 1);
+        assert!(!request.is_cancelled(), "evicting a generated chunk doesn't cancel the request");
+        assert_eq!(request.ready_chunks(), vec![Region::new([0, 0], [8, 8])]);
+
+        assert_eq!(map.generate_queued(1), 1);
+        assert!(request.wait());
+        assert!(map.is_evicted(&Region::new([0, 0], [8, 8])));
+
+        let cancelled = map.request(Region::new([0, 0], [16, 8]));
+        map.cancel(&Region::new([0, 0], [8, 8]));
+        assert!(cancelled.is_cancelled() && !cancelled.wait());
+        assert_eq!(map.lock.lock().unwrap().requests.len(), 2);
+        drop(cancelled);
+        drop(request);
+        assert!(map.lock.lock().unwrap().requests.is_empty());
+    }
+
+    #[test]
+    fn focus_ordering_and_cancellation() {
+        let map: Map<[i32; 2], u32> = Map::new(vec![Box::new(Fill)], 8, 0);
+        let queued = |map: &Map<[i32; 2], u32>| map.lock.lock().unwrap().queued.len();
+        let request = map.request(Region::new([0, 0], [64, 64]));
+        map.set_focus(0, [60, 60], 0);
+        assert_eq!(map.generate_queued(1), 1);
+        assert_eq!(map.drain_dirty_regions(), Region::new([56, 56], [64, 64]).into());
+
+        map.set_focus(1, [4, 4], 4);
+        map.generate_queued(1);
+        assert_eq!(map.drain_dirty_regions(), Region::new([0, 0], [8, 8]).into());
+        assert_eq!(queued(&map), 62, "foci don't cancel chunks an explicit request queued");
+        assert!(!request.is_cancelled());
+
+        drop(request);
+        assert_eq!(queued(&map), 3, "only the chunks around the second focus are left");
+        map.set_focus(1, [100, 4], 0);
+        assert_eq!(map.lock.lock().unwrap().queued, vec![Region::new([96, 0], [104, 8])]);
+        map.remove_focus(1);
+        assert_eq!(queued(&map), 0);
+
+        map.set_focus(2, [4, 4], 4);
+        let request = map.request(Region::new([0, 0], [8, 16]));
+        map.remove_focus(2);
+        assert_eq!(map.lock.lock().unwrap().queued, vec![Region::new([0, 8], [8, 16])]);
+        map.cancel(&Region::new([0, 0], [64, 64]));
+        assert!(request.is_cancelled() && !request.wait());
+        assert_eq!(map.generate_queued(16), 0);
+    }
+
+    #[test]
+    fn generate_while_evicting() {
+        use std::sync::atomic::AtomicBool;
+        use std::thread;
+
+        let map: Arc<Map<[i32; 2], u32>> = Arc::new(Map::new(vec![Box::new(Fill)], 8, 0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let evictor = {
+            let (map, stop) = (map.clone(), stop.clone());
+            thread::spawn(move || while !stop.load(Ordering::Relaxed) {
+                map.evict_to_budget(0, EvictionPolicy::LeastRecentlyUsed);
+            })
+        };
+        let (done, finished) = mpsc::channel();
+        for _ in 0..2 {
+            let (map, done) = (map.clone(), done.clone());
+            thread::spawn(move || {
+                for _ in 0..20_000 {
+                    map.maybe_generate(Region::new([0, 0], [16, 16]));
+                }
+                done.send(()).unwrap();
+            });
+        }
+        for _ in 0..2 {
+            finished.recv_timeout(Duration::from_secs(60)).expect("maybe_generate never returned");
+        }
+        stop.store(true, Ordering::Relaxed);
+        evictor.join().unwrap();
+    }
+
+    #[test]
+    fn eviction() {
+        let map: Map<[i32; 2], u32> = Map::new(vec![Box::new(Fill)], 8, 0);
+        map.maybe_generate(Region::new([0, 0], [32, 8]));
+        map.region(&Region::new([8, 0], [16, 8]));
+
+        let evicted = map.evict_to_budget(1, EvictionPolicy::LeastRecentlyUsed);
+        assert_eq!(evicted.len(), 3);
+        assert!(!evicted.contains(&Region::new([8, 0], [16, 8])));
+        assert!(map.is_evicted(&Region::new([0, 0], [8, 8])));
+
+        {
+            let _reader = map.region(&Region::new([8, 0], [16, 8]));
+            assert!(map.evict_to_budget(0, EvictionPolicy::FurthestFromFocus).is_empty());
+        }
+        assert_eq!(map.unload(&Region::new([0, 0], [32, 8])), vec![Region::new([8, 0], [16, 8])]);
+
+        map.maybe_generate(Region::new([0, 0], [8, 8]));
+        assert!(!map.is_evicted(&Region::new([0, 0], [8, 8])));
+        assert_eq!(*map.get(&[3, 3]), 1);
+    }
+
+    #[test]
+    fn evicts_partial_chunks() {
+        let map: Map<[i32; 2], u32> = Map::new(vec![Box::new(Fill), Box::new(Fill)], 8, 0);
+        let chunk = Region::new([0, 0], [8, 8]);
+        map.maybe_generate(chunk);
+        assert_eq!(map.chunk_stages().len(), 9);
+
+        let evicted = map.evict_to_budget(1, EvictionPolicy::LeastRecentlyUsed);
+        assert_eq!(evicted.len(), 8);
+        assert!(!evicted.contains(&chunk));
+        assert_eq!(map.stage(&Region::new([8, 0], [16, 8])), Stage::Unloaded);
+        assert!(!map.is_evicted(&Region::new([8, 0], [16, 8])));
+
+        map.maybe_generate(Region::new([8, 0], [16, 8]));
+        assert_eq!(*map.get(&[9, 1]), 2);
+        assert_eq!(map.stage(&Region::new([-8, 0], [0, 8])), Stage::Unloaded);
+        assert_eq!(map.unload(&Region::new([16, 0], [24, 8])), vec![Region::new([16, 0], [24, 8])]);
+        assert_eq!(map.chunk_stages().len(), 8);
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let map: Map<[i32; 2], u32> = Map::new(vec![Box::new(Fill)], 8, 0);
+        map.maybe_generate(Region::new([0, 0], [8, 16]));
+        *map.get_mut(&[1, 1]) = 42;
+
+        let snapshot = map.snapshot(&Region::new([0, 0], [16, 16]));
+        assert_eq!(snapshot.chunks.len(), 2);
+        #[cfg(feature = "serde")]
+        let snapshot: Snapshot<[i32; 2], u32> = bincode::deserialize(&bincode::serialize(&snapshot).unwrap()).unwrap();
+
+        *map.get_mut(&[1, 1]) = 0;
+        map.unload(&Region::new([0, 8], [8, 16]));
+        map.restore(snapshot.clone());
+        assert_eq!(*map.get(&[1, 1]), 42);
+        assert_eq!(*map.get(&[1, 9]), 1);
+
+        let other: Map<[i32; 2], u32> = Map::new(vec![], 8, 0);
+        other.restore(snapshot);
+        other.maybe_generate(Region::new([0, 0], [8, 16]));
+        assert_eq!(*other.get(&[1, 1]), 42);
+    }
+
+    // Objects are placed ten times as deep as the terrain under them
+    #[derive(Clone)]
+    struct Objects(Arc<Map<[i32; 2], u32>>);
+
+    impl Generator<[i32; 2], u64> for Objects {
+        fn generate(&mut self, chunk: &mut WriteGuard<'_, [i32; 2], u64>, core_region: &Region<[i32; 2]>, _umbra: &Region<[i32; 2]>) {
+            let terrain = chunk.layer(&*self.0);
+            for p in core_region.points() {
+                *chunk.get_mut(&p).unwrap() = *terrain.get(&p).unwrap() as u64 * 10;
+            }
+        }
+    }
+
+    #[test]
+    fn layers() {
+        let terrain: Arc<Map<[i32; 2], u32>> = Arc::new(Map::new(vec![Box::new(Fill)], 8, 0));
+        let objects: Map<[i32; 2], u64> = terrain.new_layer(vec![Box::new(Objects(terrain.clone()))]);
+        objects.maybe_generate(Region::new([0, 0], [8, 8]));
+        assert_eq!(terrain.generated_regions().area(), 24 * 24);
+        assert_eq!(*objects.get(&[3, 3]), 10);
+
+        let r = Region::new([0, 0], [8, 8]);
+        {
+            let mut region = terrain.region_mut(&r);
+            *region.get_mut(&[3, 3]).unwrap() = 4;
+            let layer = region.layer(&objects);
+            assert_eq!(*layer.get(&[3, 3]).unwrap(), 10);
+            drop(region);
+            assert!(matches!(objects.try_region(&r, Duration::from_millis(10)), Err(Error::LockTimeout)), "held by the layer's guard");
+        }
+        let region = objects.region(&Region::new([2, 2], [4, 4]));
+        assert_eq!(region.layer(&*terrain).iter().map(|(_, tile)| *tile).sum::<u32>(), 4 + 3);
+        assert!(terrain.try_region(&r, Duration::from_millis(10)).is_ok());
+        assert!(terrain.try_region_mut(&r, Duration::from_millis(10)).is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn layer_of_the_map_being_written() {
+        let terrain: Arc<Map<[i32; 2], u32>> = Arc::new(Map::new(vec![Box::new(Fill)], 8, 0));
+        let objects: Map<[i32; 2], u64> = terrain.new_layer(vec![]);
+        let region = terrain.region_mut(&Region::new([0, 0], [8, 8]));
+        region.layer(&objects).layer(&*terrain);
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Biome(i32);
+
+    #[derive(Clone)]
+    struct Biomes;
+
+    impl Generator<[i32; 2], u32> for Biomes {
+        fn generate(&mut self, chunk: &mut WriteGuard<'_, [i32; 2], u32>, core_region: &Region<[i32; 2]>, _umbra: &Region<[i32; 2]>) {
+            assert!(chunk.chunk_data::<Biome>(core_region).unwrap().is_none());
+            chunk.set_chunk_data(core_region, Biome(core_region.min[0] / 8)).unwrap();
+        }
+
+        fn umbra_width(&self) -> u32 {
+            0
+        }
+    }
+
+    // Fills each chunk with the biome of the chunk to its left and notes it alongside the chunk's
+    // own biome
+    #[derive(Clone)]
+    struct LeftBiome;
+
+    impl Generator<[i32; 2], u32> for LeftBiome {
+        fn generate(&mut self, chunk: &mut WriteGuard<'_, [i32; 2], u32>, core_region: &Region<[i32; 2]>, _umbra: &Region<[i32; 2]>) {
+            let left = Region::new([core_region.min[0] - 8, core_region.min[1]], [core_region.min[0], core_region.max[1]]);
+            assert!(matches!(chunk.chunk_data_mut::<Biome>(&left), Err(Error::OutOfRegion)));
+            let biome = chunk.chunk_data::<Biome>(&left).unwrap().unwrap().0;
+            chunk.for_each_mut(core_region, |_, tile| *tile = biome as u32);
+            chunk.set_chunk_data(core_region, biome as u32).unwrap();
+            *chunk.chunk_data_mut::<u32>(core_region).unwrap().unwrap() += 10;
+        }
+    }
+
+    #[test]
+    fn chunk_data() {
+        let map: Map<[i32; 2], u32> = Map::new(vec![Box::new(Biomes), Box::new(LeftBiome)], 8, 0);
+        map.maybe_generate(Region::new([8, 0], [24, 8]));
+        assert_eq!(*map.get(&[20, 3]), 1);
+
+        let row = Region::new([0, 0], [24, 8]);
+        let mut biomes = map.chunk_data::<Biome>(&row);
+        biomes.sort_by_key(|(chunk, _)| chunk.min[0]);
+        assert_eq!(biomes, vec![
+            (Region::new([0, 0], [8, 8]), Biome(0)),
+            (Region::new([8, 0], [16, 8]), Biome(1)),
+            (Region::new([16, 0], [24, 8]), Biome(2)),
+        ]);
+        assert_eq!(map.chunk_data::<u32>(&Region::new([16, 0], [17, 1])), vec![(Region::new([16, 0], [24, 8]), 11)]);
+        assert!(map.chunk_data::<u8>(&row).is_empty());
+
+        let chunk = Region::new([16, 0], [24, 8]);
+        {
+            let mut region = map.region_mut(&chunk);
+            assert_eq!(region.set_chunk_data(&chunk, Biome(5)).unwrap(), Some(Biome(2)));
+            assert!(matches!(region.chunk_data::<Biome>(&Region::new([24, 0], [32, 8])), Err(Error::OutOfRegion)));
+        }
+        assert_eq!(map.region(&Region::new([20, 0], [21, 1])).chunk_data(&chunk).unwrap(), Some(&Biome(5)));
+        let unloaded = Region::new([40, 0], [48, 8]);
+        assert!(matches!(map.region(&unloaded).chunk_data::<Biome>(&unloaded), Err(Error::NotGenerated)));
+
+        map.unload(&chunk);
+        assert_eq!(map.chunk_data::<Biome>(&row).len(), 2);
+    }
+
+    #[test]
+    fn change_subscriptions() {
+        let map: Map<[i32; 2], u32> = Map::new(vec![Box::new(Fill)], 8, 0);
+        let left = map.subscribe(&Region::new([0, 0], [8, 8]));
+        let right = map.subscribe(&Region::new([8, 0], [16, 8]));
+        map.maybe_generate(Region::new([0, 0], [16, 8]));
+        assert!(left.try_recv().is_err(), "generation isn't reported");
+
+        *map.get_mut(&[3, 3]) += 1;
+        let _tile = *map.get_mut(&[4, 4]);
+        assert_eq!(left.try_recv().unwrap(), Region::new([3, 3], [4, 4]).into());
+        assert!(left.try_recv().is_err());
+
+        {
+            let mut region = map.region_mut(&Region::new([6, 0], [10, 8]));
+            region.for_each_mut(&Region::new([0, 2], [16, 4]), |_, tile| *tile += 1);
+            *region.get_mut(&[9, 7]).unwrap() = 0;
+            assert!(right.try_recv().is_err(), "reported once the guard is dropped");
+        }
+        let changed = left.try_recv().unwrap();
+        assert_eq!((changed.area(), changed.contains(&[6, 2]), changed.contains(&[7, 3])), (4, true, true));
+        let changed = right.try_recv().unwrap();
+        assert_eq!((changed.area(), changed.contains(&[9, 7])), (5, true));
+        assert!(left.try_recv().is_err() && right.try_recv().is_err());
+
+        drop(right);
+        *map.get_mut(&[9, 1]) = 0;
+        assert_eq!(map.subscribers.lock().unwrap().len(), 1);
+    }
+
+    #[derive(Clone)]
+    struct Smear;
+
+    impl Generator<[i32; 2], u32> for Smear {
+        fn generate(&mut self, chunk: &mut WriteGuard<'_, [i32; 2], u32>, _core_region: &Region<[i32; 2]>, umbra: &Region<[i32; 2]>) {
+            chunk.for_each_mut(umbra, |_, tile| *tile += 1);
+        }
+    }
+
+    #[test]
+    fn generator_and_restore_writes_are_reported() {
+        let map: Map<[i32; 2], u32> = Map::new(vec![Box::new(Smear)], 8, 0);
+        let left = map.subscribe(&Region::new([0, 0], [8, 8]));
+        let right = map.subscribe(&Region::new([8, 0], [16, 8]));
+        map.maybe_generate(Region::new([0, 0], [8, 8]));
+        let snapshot = map.snapshot(&Region::new([0, 0], [8, 8]));
+        assert!(left.try_recv().is_err());
+
+        map.maybe_generate(Region::new([8, 0], [16, 8]));
+        assert_eq!(left.try_recv().unwrap(), Region::new([7, 0], [8, 8]).into(), "the generator wrote into its umbra");
+        assert_eq!(*map.get(&[7, 3]), 2);
+        assert!(right.try_recv().is_err(), "the chunk being generated isn't reported");
+
+        map.restore(snapshot);
+        assert_eq!(left.try_recv().unwrap(), Region::new([0, 0], [8, 8]).into());
+        assert!(right.try_recv().is_err());
+        assert_eq!(*map.get(&[7, 3]), 1);
+    }
+
+    #[test]
+    fn repeated_writes_are_reported_once() {
+        let map: Map<[i32; 2], u32> = Map::new(vec![Box::new(Fill)], 8, 0);
+        map.maybe_generate(Region::new([0, 0], [8, 8]));
+        let changes = map.subscribe(&Region::new([0, 0], [8, 8]));
+        {
+            let mut region = map.region_mut(&Region::new([0, 0], [8, 8]));
+            for _ in 0..3 {
+                region.for_each_mut(&Region::new([0, 0], [4, 4]), |_, tile| *tile += 1);
+                *region.get_mut(&[1, 1]).unwrap() += 1;
+                *region.get_mut(&[5, 5]).unwrap() += 1;
+            }
+        }
+        let changed = changes.try_recv().unwrap();
+        assert_eq!((changed.area(), changed.contains(&[5, 5])), (17, true));
+    }
+
+    // Holds up generating the chunk at the origin until the test lets it go
+    #[derive(Clone)]
+    struct Gate(Arc<std::sync::Barrier>);
+
+    impl Generator<[i32; 2], u32> for Gate {
+        fn generate(&mut self, _chunk: &mut WriteGuard<'_, [i32; 2], u32>, core_region: &Region<[i32; 2]>, _umbra: &Region<[i32; 2]>) {
+            if core_region.min == [0, 0] {
+                self.0.wait();
+                self.0.wait();
+            }
+        }
+
+        fn umbra_width(&self) -> u32 {
+            0
+        }
+    }
+
+    #[test]
+    fn restore_waits_for_generation() {
+        use std::thread;
+
+        let chunk = Region::new([0, 0], [8, 8]);
+        let source: Map<[i32; 2], u32> = Map::new(vec![], 8, 0);
+        source.maybe_generate(chunk);
+        *source.get_mut(&[1, 1]) = 42;
+        let snapshot = source.snapshot(&chunk);
+
+        let gate = Arc::new(std::sync::Barrier::new(2));
+        let map: Arc<Map<[i32; 2], u32>> = Arc::new(Map::new(vec![Box::new(Gate(gate.clone())), Box::new(Fill)], 8, 0));
+        let generating = {
+            let map = map.clone();
+            thread::spawn(move || map.maybe_generate(chunk))
+        };
+        gate.wait();
+        let restoring = {
+            let map = map.clone();
+            thread::spawn(move || map.restore(snapshot))
+        };
+        thread::sleep(Duration::from_millis(50));
+        gate.wait();
+        generating.join().unwrap();
+        restoring.join().unwrap();
+        assert_eq!(*map.get(&[1, 1]), 42);
+        assert_eq!(*map.get(&[2, 2]), 0);
+    }
+
+    #[test]
+    fn hash_storage() {
+        let map: Map<[i32; 2], u32, HashStorage<_, _>> = Map::with_storage(vec![Box::new(Fill)], 8, 0);
+        map.maybe_generate(Region::new([0, 0], [8, 16]));
+        assert_eq!(*map.get(&[3, 3]), 1);
+        {
+            let mut region = map.region_mut(&Region::new([4, 4], [12, 12]));
+            region.for_each_mut(&Region::new([0, 0], [16, 16]), |_, tile| *tile += 1);
+            assert_eq!(region.iter().count(), 32);
+            assert!(matches!(region.get(&[9, 9]), Err(Error::NotGenerated)));
+        }
+        assert_eq!(*map.get(&[5, 9]), 2);
+
+        let snapshot = map.snapshot(&Region::new([0, 0], [8, 16]));
+        map.unload(&Region::new([0, 8], [8, 16]));
+        assert!(matches!(map.try_get(&[5, 9]), Err(Error::Evicted)));
+        map.restore(snapshot);
+        assert_eq!(*map.get(&[5, 9]), 2);
+    }
 }
@@ -0,0 +1,226 @@
+use crate::{
+    hex::Hex,
+    point::{Point, neighboors_26},
+    wrapping::Wrapping,
+};
+
+// Which points count as adjacent to a point, for analyses like `Connectivity`
+pub trait Neighbourhood<P>: Send + Sync {
+    fn neighbours(&self, p: &P) -> Vec<P>;
+
+    // How many tiles away from the point its furthest neighbour can be. A generator which looks
+    // at the neighbours of its core needs an umbra at least this wide.
+    fn reach(&self) -> u32;
+}
+
+// The point type's own adjacency, `Point::neighboors`: 4-way on a grid, 6-way for voxels and hexes
+#[derive(Copy, Clone, Debug, Default)]
+pub struct VonNeumann;
+
+impl<P: Point> Neighbourhood<P> for VonNeumann {
+    fn neighbours(&self, p: &P) -> Vec<P> {
+        p.neighboors()
+    }
+
+    fn reach(&self) -> u32 {
+        1
+    }
+}
+
+// Every point touching this one, including diagonally: 8-way on a grid, 26-way for voxels. Hexes
+// have no diagonals so this is the same as `VonNeumann` for them.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Moore;
+
+const MOORE: [[i32; 2]; 8] = [[-1, -1], [0, -1], [1, -1], [-1, 0], [1, 0], [-1, 1], [0, 1], [1, 1]];
+
+impl Neighbourhood<[i32; 2]> for Moore {
+    fn neighbours(&self, p: &[i32; 2]) -> Vec<[i32; 2]> {
+        MOORE.iter().map(|offset| p.add(offset)).collect()
+    }
+
+    fn reach(&self) -> u32 {
+        1
+    }
+}
+
+impl Neighbourhood<[i32; 3]> for Moore {
+    fn neighbours(&self, p: &[i32; 3]) -> Vec<[i32; 3]> {
+        neighboors_26(p)
+    }
+
+    fn reach(&self) -> u32 {
+        1
+    }
+}
+
+impl Neighbourhood<Hex> for Moore {
+    fn neighbours(&self, p: &Hex) -> Vec<Hex> {
+        p.neighboors()
+    }
+
+    fn reach(&self) -> u32 {
+        1
+    }
+}
+
+impl<const WIDTH: u32, const HEIGHT: u32> Neighbourhood<Wrapping<WIDTH, HEIGHT>> for Moore {
+    fn neighbours(&self, p: &Wrapping<WIDTH, HEIGHT>) -> Vec<Wrapping<WIDTH, HEIGHT>> {
+        MOORE.iter().map(|[dx, dy]| p.add(&Wrapping::new(*dx, *dy))).collect()
+    }
+
+    fn reach(&self) -> u32 {
+        1
+    }
+}
+
+// The eight moves of a chess knight
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Knight;
+
+const KNIGHT: [[i32; 2]; 8] = [[1, 2], [2, 1], [2, -1], [1, -2], [-1, -2], [-2, -1], [-2, 1], [-1, 2]];
+
+impl Neighbourhood<[i32; 2]> for Knight {
+    fn neighbours(&self, p: &[i32; 2]) -> Vec<[i32; 2]> {
+        KNIGHT.iter().map(|offset| p.add(offset)).collect()
+    }
+
+    fn reach(&self) -> u32 {
+        2
+    }
+}
+
+impl<const WIDTH: u32, const HEIGHT: u32> Neighbourhood<Wrapping<WIDTH, HEIGHT>> for Knight {
+    fn neighbours(&self, p: &Wrapping<WIDTH, HEIGHT>) -> Vec<Wrapping<WIDTH, HEIGHT>> {
+        KNIGHT.iter().map(|[dx, dy]| p.add(&Wrapping::new(*dx, *dy))).collect()
+    }
+
+    fn reach(&self) -> u32 {
+        2
+    }
+}
+
+// An arbitrary stencil, given as offsets from the point
+#[derive(Clone, Debug)]
+pub struct Offsets<P> {
+    offsets: Vec<P>,
+    reach: u32,
+}
+
+impl<P: Point + OffsetReach> Offsets<P> {
+    pub fn new(offsets: Vec<P>) -> Self {
+        let reach = offsets.iter().map(OffsetReach::offset_reach).max().unwrap_or(0);
+        Self {
+            offsets,
+            reach,
+        }
+    }
+}
+
+// How far an offset moves a point, in the margins `Region::expand` adds: the largest change to any
+// one coordinate
+pub trait OffsetReach {
+    fn offset_reach(&self) -> u32;
+}
+
+impl<const N: usize> OffsetReach for [i32; N] {
+    fn offset_reach(&self) -> u32 {
+        self.iter().map(|c| c.unsigned_abs()).max().unwrap_or(0)
+    }
+}
+
+impl<const WIDTH: u32, const HEIGHT: u32> OffsetReach for Wrapping<WIDTH, HEIGHT> {
+    fn offset_reach(&self) -> u32 {
+        self.x.unsigned_abs().max(self.y.unsigned_abs())
+    }
+}
+
+// Hex regions are boxes in offset coordinates, where the same axial offset moves the column by a
+// different amount on even and odd rows, so this takes the larger of the two
+impl OffsetReach for Hex {
+    fn offset_reach(&self) -> u32 {
+        [Hex::from_offset(0, 0), Hex::from_offset(0, 1)].iter().map(|origin| {
+            let [col, row] = origin.to_offset();
+            let [to_col, to_row] = origin.add(self).to_offset();
+            (to_col - col).unsigned_abs().max((to_row - row).unsigned_abs())
+        }).max().unwrap()
+    }
+}
+
+impl<P: Point> Neighbourhood<P> for Offsets<P> {
+    fn neighbours(&self, p: &P) -> Vec<P> {
+        self.offsets.iter().map(|offset| p.add(offset)).collect()
+    }
+
+    fn reach(&self) -> u32 {
+        self.reach
+    }
+}
+
+// What it takes to move diagonally between two tiles which aren't `Point::neighboors` of each
+// other but share some, like the two orthogonal tiles around a diagonal step on a grid
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum CornerCutting {
+    #[default]
+    Always,
+    // At least one of the shared neighboors is open
+    IfEitherOpen,
+    // None of the shared neighboors are blocked
+    IfBothOpen,
+}
+
+// The tiles a step from `a` to `b` squeezes past. Empty for steps to one of `a`'s own neighboors
+// and for jumps, like a knight's, which don't pass next to any tile in particular.
+pub fn corners<P: Point>(a: &P, b: &P) -> Vec<P> {
+    let around_a = a.neighboors();
+    if around_a.contains(b) {
+        return vec![];
+    }
+    let around_b = b.neighboors();
+    around_a.into_iter().filter(|p| around_b.contains(p)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets_reach() {
+        assert_eq!(Offsets::new(vec![[0, 1], [3, -1]]).reach(), 3);
+        assert_eq!(Offsets::new(vec![[-2, 0, 1]]).reach(), 2);
+        assert_eq!(Offsets::<[i32; 2]>::new(vec![]).reach(), 0);
+        assert_eq!(Offsets::new(vec![[1, 2]]).neighbours(&[5, 5]), vec![[6, 7]]);
+        assert_eq!(Offsets::new(vec![Wrapping::<16, 0>::new(-5, 2)]).reach(), 5);
+    }
+
+    #[test]
+    fn hex_offsets_reach() {
+        let offsets = Offsets::new(vec![Hex::new(1, 1), Hex::new(-1, 0)]);
+        let reach = offsets.reach();
+        assert_eq!(reach, 2);
+        for origin in [Hex::from_offset(3, 4), Hex::from_offset(3, 5)] {
+            let margin = origin.to_cube(1).expand(reach);
+            assert!(offsets.neighbours(&origin).iter().all(|n| n.contained(&margin)));
+        }
+    }
+
+    #[test]
+    fn diagonal_corners() {
+        let mut around = corners(&[0, 0], &[1, 1]);
+        around.sort();
+        assert_eq!(around, vec![[0, 1], [1, 0]]);
+        assert!(corners(&[0, 0], &[1, 0]).is_empty());
+        assert!(corners(&[0, 0], &[2, 1]).is_empty());
+        assert_eq!(corners(&[0, 0, 0], &[1, 0, 1]).len(), 2);
+        assert!(Moore.neighbours(&Hex::new(0, 0)).iter().all(|n| corners(&Hex::new(0, 0), n).is_empty()));
+    }
+
+    #[test]
+    fn wrapping_neighbours() {
+        type Cylinder = Wrapping<16, 0>;
+        let p = Cylinder::new(15, 0);
+        assert!(Moore.neighbours(&p).contains(&Cylinder::new(0, 1)));
+        assert!(Knight.neighbours(&p).contains(&Cylinder::new(1, 1)));
+        assert_eq!(Neighbourhood::<[i32; 2]>::neighbours(&Moore, &[0, 0]).len(), 8);
+    }
+}
@@ -1,36 +1,191 @@
-use noise::{Fbm, Seedable, NoiseFn};
+use noise::{Fbm, Seedable, NoiseFn, MultiFractal};
 use rand::{
     Rng,
 };
 
+use std::f64::consts::PI;
+
 use super::{
     generator::Generator, WriteGuard,
-    point::Point, analysis::Passable,
+    analysis::Passable, region::Region,
+    storage::Storage,
+    wrapping::Wrapping,
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FbmGenerator {
     noise: Fbm,
 }
 
 impl FbmGenerator {
     pub fn new(octaves: usize, persistence: f64, frequency: f64) -> Self {
-        let mut noise = Fbm::new().set_seed(rand::thread_rng().gen());
-        noise.octaves = octaves;
-        noise.persistence = persistence;
-        noise.frequency = frequency;
+        Self::with_seed(rand::thread_rng().gen(), octaves, persistence, frequency)
+    }
+
+    pub fn with_seed(seed: u32, octaves: usize, persistence: f64, frequency: f64) -> Self {
+        let noise = Fbm::new()
+            .set_seed(seed)
+            .set_octaves(octaves)
+            .set_persistence(persistence)
+            .set_frequency(frequency);
         Self {
             noise,
         }
     }
 }
 
-impl<T: Passable> Generator<[i32; 2], T> for FbmGenerator {
-    fn generate(&mut self, chunk: &mut WriteGuard<'_, [i32; 2], T>, core_region: &[[i32; 2]; 2], _umbra: &[[i32; 2]; 2]) {
-        for p in <[i32; 2] as Point>::points_in_region(core_region) {
-            let mut tile = chunk.get_mut(&p).unwrap();
-            let n = self.noise.get([p[0] as f64, p[1] as f64]);
-            tile.set_passable(n > 0.1);
+impl<T: Passable, S: Storage<[i32; 2], T>> Generator<[i32; 2], T, S> for FbmGenerator {
+    fn generate(&mut self, chunk: &mut WriteGuard<'_, [i32; 2], T, S>, core_region: &Region<[i32; 2]>, _umbra: &Region<[i32; 2]>) {
+        chunk.for_each_mut(core_region, |p, tile| {
+            tile.set_passable(self.noise.get([p[0] as f64, p[1] as f64]) > 0.1);
+        });
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.noise = self.noise.clone().set_seed(seed as u32);
+    }
+
+    fn umbra_width(&self) -> u32 {
+        0
+    }
+}
+
+impl<T: Passable, const WIDTH: u32, const HEIGHT: u32, S: Storage<Wrapping<WIDTH, HEIGHT>, T>> Generator<Wrapping<WIDTH, HEIGHT>, T, S> for FbmGenerator {
+    fn generate(&mut self, chunk: &mut WriteGuard<'_, Wrapping<WIDTH, HEIGHT>, T, S>, core_region: &Region<Wrapping<WIDTH, HEIGHT>>, _umbra: &Region<Wrapping<WIDTH, HEIGHT>>) {
+        chunk.for_each_mut(core_region, |p, tile| tile.set_passable(self.sample_wrapped(p) > 0.1));
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.noise = self.noise.clone().set_seed(seed as u32);
+    }
+
+    fn umbra_width(&self) -> u32 {
+        0
+    }
+}
+
+impl FbmGenerator {
+    // Samples the noise on a cylinder or torus so that it is seamless across the wrapped axes. The
+    // radius is chosen so that distances along the surface match the flat case.
+    fn sample_wrapped<const WIDTH: u32, const HEIGHT: u32>(&self, p: &Wrapping<WIDTH, HEIGHT>) -> f64 {
+        let circle = |v: i32, size: u32| {
+            let radius = size as f64 / (2.0 * PI);
+            let angle = v as f64 / radius;
+            (angle.cos() * radius, angle.sin() * radius)
+        };
+        let p = p.normalized();
+        match (WIDTH, HEIGHT) {
+            (0, 0) => self.noise.get([p.x as f64, p.y as f64]),
+            (_, 0) => {
+                let (a, b) = circle(p.x, WIDTH);
+                self.noise.get([a, b, p.y as f64])
+            },
+            (0, _) => {
+                let (a, b) = circle(p.y, HEIGHT);
+                self.noise.get([p.x as f64, a, b])
+            },
+            (_, _) => {
+                let (a, b) = circle(p.x, WIDTH);
+                let (c, d) = circle(p.y, HEIGHT);
+                self.noise.get([a, b, c, d])
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+    use crate::Map;
+
+    #[derive(Default)]
+    struct Tile {
+        passable: bool,
+    }
+
+    impl Passable for Tile {
+        fn is_passable(&self) -> bool {
+            self.passable
+        }
+
+        fn set_passable(&mut self, passable: bool) {
+            self.passable = passable;
+        }
+    }
+
+    fn new_map(seed: u64) -> Map<[i32; 2], Tile> {
+        Map::new(vec![Box::new(FbmGenerator::new(4, 0.5, 0.05))], 16, seed)
+    }
+
+    fn dump(map: &Map<[i32; 2], Tile>, r: &Region<[i32; 2]>) -> Vec<u8> {
+        let region = map.region(r);
+        region.iter().map(|(_, tile)| tile.passable as u8).collect()
+    }
+
+    #[test]
+    fn same_seed_same_world() {
+        let chunks = [
+            Region::new([0, 0], [16, 16]),
+            Region::new([16, 0], [32, 16]),
+            Region::new([0, 16], [16, 32]),
+            Region::new([-16, -16], [0, 0]),
+        ];
+        let whole = Region::new([-16, -16], [32, 32]);
+
+        let forward = new_map(1234);
+        for chunk in &chunks {
+            forward.maybe_generate(chunk);
+        }
+        forward.maybe_generate(whole);
+
+        let backward = new_map(1234);
+        backward.maybe_generate(whole);
+        for chunk in chunks.iter().rev() {
+            backward.maybe_generate(chunk);
+        }
+
+        let threaded = Arc::new(new_map(1234));
+        let handles: Vec<_> = chunks.iter().cloned().map(|chunk| {
+            let map = threaded.clone();
+            thread::spawn(move || map.maybe_generate(chunk))
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        threaded.maybe_generate(whole);
+
+        let expected = dump(&forward, &whole);
+        assert!(expected.contains(&0) && expected.contains(&1));
+        assert_eq!(expected, dump(&backward, &whole));
+        assert_eq!(expected, dump(&threaded, &whole));
+
+        let other = new_map(4321);
+        other.maybe_generate(whole);
+        assert_ne!(expected, dump(&other, &whole));
+    }
+
+    #[test]
+    fn seamless_across_the_seam() {
+        type Torus = Wrapping<64, 32>;
+        let generator = FbmGenerator::with_seed(7, 4, 0.5, 0.2);
+        let step = |a: Torus, b: Torus| (generator.sample_wrapped(&a) - generator.sample_wrapped(&b)).abs();
+
+        let mut largest_step = 0.0f64;
+        for y in 0..31 {
+            for x in 0..63 {
+                largest_step = largest_step.max(step(Torus::new(x, y), Torus::new(x + 1, y)));
+                largest_step = largest_step.max(step(Torus::new(x, y), Torus::new(x, y + 1)));
+            }
+        }
+        for y in 0..32 {
+            assert!(generator.sample_wrapped(&Torus::new(-1, y)) == generator.sample_wrapped(&Torus::new(63, y)));
+            assert!(step(Torus::new(63, y), Torus::new(64, y)) <= largest_step);
+        }
+        for x in 0..64 {
+            assert!(step(Torus::new(x, 31), Torus::new(x, 32)) <= largest_step);
         }
     }
 }
@@ -0,0 +1,207 @@
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+use crate::sparse::ChunkTiles;
+
+// A chunk's tiles stored as indices into a palette of the distinct values in the chunk, packed
+// as tightly as the palette's size allows. A chunk holding a single value stores just that value.
+// Meant for tiles drawn from a small set, like wall/floor or a handful of biomes; every distinct
+// value costs a linear search when setting tiles.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Palette<T> {
+    palette: Vec<T>,
+    // Bits per index, a power of two so indices never straddle words. Zero while there's only
+    // one value, in which case `words` is empty.
+    bits: u32,
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl<T: Clone + PartialEq> Palette<T> {
+    pub fn filled(len: usize, value: T) -> Self {
+        Self {
+            palette: vec![value],
+            bits: 0,
+            words: vec![],
+            len,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Distinct values which have been stored in the chunk, including ones since overwritten
+    pub fn palette(&self) -> &[T] {
+        &self.palette
+    }
+
+    pub fn bits_per_tile(&self) -> u32 {
+        self.bits
+    }
+
+    pub fn get(&self, i: usize) -> &T {
+        assert!(i < self.len, "Tile {} is outside a chunk of {} tiles", i, self.len);
+        &self.palette[self.index(i)]
+    }
+
+    pub fn set(&mut self, i: usize, value: T) {
+        assert!(i < self.len, "Tile {} is outside a chunk of {} tiles", i, self.len);
+        let index = match self.palette.iter().position(|other| *other == value) {
+            Some(index) => index,
+            None => {
+                self.palette.push(value);
+                if self.palette.len() > 1 << self.bits {
+                    self.repack();
+                }
+                self.palette.len() - 1
+            },
+        };
+        if self.bits > 0 {
+            let (word, shift) = self.position(i);
+            let mask = self.mask() << shift;
+            self.words[word] = (self.words[word] & !mask) | ((index as u64) << shift);
+        }
+    }
+
+    fn index(&self, i: usize) -> usize {
+        if self.bits == 0 {
+            return 0;
+        }
+        let (word, shift) = self.position(i);
+        ((self.words[word] >> shift) & self.mask()) as usize
+    }
+
+    fn position(&self, i: usize) -> (usize, u32) {
+        let per_word = (64 / self.bits) as usize;
+        (i / per_word, (i % per_word) as u32 * self.bits)
+    }
+
+    fn mask(&self) -> u64 {
+        if self.bits == 64 {
+            !0
+        } else {
+            (1 << self.bits) - 1
+        }
+    }
+
+    // Drops palette entries no tile uses any more, narrowing the indices to fit. A chunk left
+    // with a single value goes back to storing just that value.
+    pub fn compact(&mut self) {
+        let mut remap = vec![None; self.palette.len()];
+        let mut palette = vec![];
+        let indices = (0..self.len).map(|i| {
+            let index = self.index(i);
+            *remap[index].get_or_insert_with(|| {
+                palette.push(self.palette[index].clone());
+                palette.len() - 1
+            })
+        }).collect();
+        self.palette = palette;
+        self.pack(indices);
+    }
+
+    // Widens the indices to fit the palette
+    fn repack(&mut self) {
+        let indices = (0..self.len).map(|i| self.index(i)).collect();
+        self.pack(indices);
+    }
+
+    fn pack(&mut self, indices: Vec<usize>) {
+        if self.palette.len() <= 1 {
+            self.bits = 0;
+            self.words = vec![];
+            return;
+        }
+        let needed = usize::BITS - (self.palette.len() - 1).leading_zeros();
+        self.bits = needed.next_power_of_two();
+        let per_word = (64 / self.bits) as usize;
+        self.words = vec![0; self.len.div_ceil(per_word)];
+        for (i, index) in indices.into_iter().enumerate() {
+            let (word, shift) = self.position(i);
+            self.words[word] |= (index as u64) << shift;
+        }
+    }
+}
+
+impl<T: Default + Clone + PartialEq> ChunkTiles<T> for Palette<T> {
+    fn new(len: usize) -> Self {
+        Self::filled(len, T::default())
+    }
+
+    fn get(&self, i: usize) -> &T {
+        Palette::get(self, i)
+    }
+
+    fn set(&mut self, i: usize, tile: T) {
+        Palette::set(self, i, tile)
+    }
+
+    fn replace(&mut self, i: usize, tile: T) -> T {
+        let old = self.get(i).clone();
+        self.set(i, tile);
+        old
+    }
+
+    fn heap_size(&self) -> usize {
+        self.palette.capacity() * std::mem::size_of::<T>() + self.words.capacity() * std::mem::size_of::<u64>()
+    }
+
+    fn compact(&mut self) {
+        Palette::compact(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grows_as_values_are_added() {
+        let mut chunk = Palette::filled(100, 0u32);
+        assert_eq!(chunk.bits_per_tile(), 0);
+        assert!(chunk.words.is_empty());
+
+        let mut expected = vec![0; 100];
+        for (values, bits) in [(2, 1), (3, 2), (4, 2), (5, 4), (17, 8), (300, 16)] {
+            for value in 0..values {
+                chunk.set(value as usize % 100, value);
+                expected[value as usize % 100] = value;
+            }
+            assert_eq!(chunk.palette().len(), values as usize);
+            assert_eq!(chunk.bits_per_tile(), bits);
+            assert!((0..100).all(|i| *chunk.get(i) == expected[i]));
+        }
+    }
+
+    #[test]
+    fn uniform_chunks() {
+        let mut chunk = Palette::filled(4096, "floor");
+        chunk.set(17, "floor");
+        assert_eq!(chunk.bits_per_tile(), 0);
+        chunk.set(17, "wall");
+        assert_eq!(chunk.bits_per_tile(), 1);
+        assert_eq!(chunk.words.len(), 64);
+        assert_eq!((*chunk.get(16), *chunk.get(17)), ("floor", "wall"));
+
+        for i in 0..3 {
+            chunk.set(i, "door");
+        }
+        chunk.set(17, "floor");
+        chunk.compact();
+        assert_eq!(chunk.palette(), &["door", "floor"]);
+        assert_eq!((*chunk.get(2), *chunk.get(3), *chunk.get(17)), ("door", "floor", "floor"));
+        for i in 0..3 {
+            chunk.set(i, "floor");
+        }
+        chunk.compact();
+        assert_eq!(chunk.bits_per_tile(), 0);
+        assert!(chunk.words.is_empty());
+        assert_eq!(*chunk.get(4000), "floor");
+    }
+}
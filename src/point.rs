@@ -1,39 +1,189 @@
-use std::hash::Hash;
+use std::hash::{Hash, Hasher};
 use std::iter::from_fn;
 
-pub trait Point: Hash+Eq+Sized+Clone+std::fmt::Debug+Send {
-    fn to_cube(&self, size: u32) -> [Self; 2];
-    fn overlap_rect(a: &[Self; 2], other: &[Self; 2]) -> bool;
-    fn expand(r: &[Self; 2], margin: u32) -> [Self; 2];
-    fn contained(&self, r: &[Self; 2]) -> bool;
+use crate::region::Region;
+
+pub trait Point: Hash+Eq+Sized+Clone+std::fmt::Debug+Send+Sync {
+    fn to_cube(&self, size: u32) -> Region<Self>;
+    fn overlap_rect(a: &Region<Self>, other: &Region<Self>) -> bool;
+    fn intersect(a: &Region<Self>, other: &Region<Self>) -> Option<Region<Self>>;
+    fn bounding_box(a: &Region<Self>, other: &Region<Self>) -> Region<Self>;
+    fn area(r: &Region<Self>) -> usize;
+    fn split(r: &Region<Self>) -> Option<[Region<Self>; 2]>;
+    // Disjoint regions covering the points of `a` which are not in `other`. The default halves `a`
+    // until each piece is either clear of `other` or inside it.
+    fn difference(a: &Region<Self>, other: &Region<Self>) -> Vec<Region<Self>> {
+        match Self::intersect(a, other) {
+            None if Self::area(a) == 0 => vec![],
+            None => vec![a.clone()],
+            Some(i) if Self::area(&i) == Self::area(a) => vec![],
+            Some(_) => match Self::split(a) {
+                Some([low, high]) => {
+                    let mut pieces = Self::difference(&low, other);
+                    pieces.extend(Self::difference(&high, other));
+                    pieces
+                }
+                None => vec![],
+            },
+        }
+    }
+    fn expand(r: &Region<Self>, margin: u32) -> Region<Self>;
+    fn contained(&self, r: &Region<Self>) -> bool;
     fn chunk_index(&self, chunk_size: u32) -> (Self, usize);
     fn max_unrolled_index(chunk_size: u32) -> usize;
-    fn chunks_in_region(r: &[Self; 2], chunk_size: u32) -> Vec<[Self; 2]>;
-    fn points_in_region(r: &[Self; 2]) -> Vec<Self>;
+    fn chunks_in_region(r: &Region<Self>, chunk_size: u32) -> Vec<Region<Self>>;
+    fn points_in_region(r: &Region<Self>) -> Vec<Self>;
     fn neighboors(&self) -> Vec<Self>;
+    fn distance_to_region(&self, r: &Region<Self>) -> f64;
+    fn add(&self, other: &Self) -> Self;
     fn mul(&self, m: i32) -> Self;
     fn div(&self, m: i32) -> Self;
+    // Panics if chunks of this size can't be laid out over the points. Called once when a map or
+    // storage is built so `chunk_index` doesn't have to check.
+    fn check_chunk_size(_chunk_size: u32) {}
+    // Region equality and hashing. Field by field by default, point types where different corners
+    // can describe the same set of points override both.
+    fn same_region(a: &Region<Self>, other: &Region<Self>) -> bool {
+        a.min == other.min && a.max == other.max
+    }
+    fn hash_region<H: Hasher>(r: &Region<Self>, state: &mut H) {
+        r.min.hash(state);
+        r.max.hash(state);
+    }
+}
+
+// Set operations shared by the boxes of every dimension
+
+fn box_is_empty<const N: usize>(r: &Region<[i32; N]>) -> bool {
+    (0..N).any(|i| r.max[i] <= r.min[i])
+}
+
+fn box_intersect<const N: usize>(a: &Region<[i32; N]>, other: &Region<[i32; N]>) -> Option<Region<[i32; N]>> {
+    let mut r = *a;
+    for i in 0..N {
+        r.min[i] = a.min[i].max(other.min[i]);
+        r.max[i] = a.max[i].min(other.max[i]);
+    }
+    if box_is_empty(&r) {
+        None
+    } else {
+        Some(r)
+    }
+}
+
+fn box_bounding_box<const N: usize>(a: &Region<[i32; N]>, other: &Region<[i32; N]>) -> Region<[i32; N]> {
+    if box_is_empty(a) {
+        return *other;
+    }
+    if box_is_empty(other) {
+        return *a;
+    }
+    let mut r = *a;
+    for i in 0..N {
+        r.min[i] = a.min[i].min(other.min[i]);
+        r.max[i] = a.max[i].max(other.max[i]);
+    }
+    r
+}
+
+fn box_area<const N: usize>(r: &Region<[i32; N]>) -> usize {
+    (0..N).map(|i| (r.max[i] - r.min[i]).max(0) as usize).product()
+}
+
+// Splits across the longest axis
+fn box_split<const N: usize>(r: &Region<[i32; N]>) -> Option<[Region<[i32; N]>; 2]> {
+    if box_is_empty(r) {
+        return None;
+    }
+    let axis = (0..N).max_by_key(|i| (r.max[*i] - r.min[*i], N - i)).unwrap();
+    let length = r.max[axis] - r.min[axis];
+    if length < 2 {
+        return None;
+    }
+    let mut low = *r;
+    let mut high = *r;
+    low.max[axis] = r.min[axis] + length / 2;
+    high.min[axis] = low.max[axis];
+    Some([low, high])
+}
+
+// Slices off the parts of `a` on either side of the intersection, one axis at a time
+fn box_difference<const N: usize>(a: &Region<[i32; N]>, other: &Region<[i32; N]>) -> Vec<Region<[i32; N]>> {
+    let i = match box_intersect(a, other) {
+        Some(i) => i,
+        None if box_is_empty(a) => return vec![],
+        None => return vec![*a],
+    };
+    let mut rest = *a;
+    let mut pieces = vec![];
+    for axis in 0..N {
+        if rest.min[axis] < i.min[axis] {
+            let mut piece = rest;
+            piece.max[axis] = i.min[axis];
+            pieces.push(piece);
+            rest.min[axis] = i.min[axis];
+        }
+        if i.max[axis] < rest.max[axis] {
+            let mut piece = rest;
+            piece.min[axis] = i.max[axis];
+            pieces.push(piece);
+            rest.max[axis] = i.max[axis];
+        }
+    }
+    pieces
+}
+
+// Coordinate by coordinate rather than through the array's own `Hash`, which hashes the in-memory
+// bytes and so would give seeds salted with regions a different value on big-endian hosts
+fn box_hash<const N: usize, H: Hasher>(r: &Region<[i32; N]>, state: &mut H) {
+    for c in r.min.iter().chain(&r.max) {
+        state.write_i32(*c);
+    }
+}
+
+fn box_distance<const N: usize>(p: &[i32; N], r: &Region<[i32; N]>) -> f64 {
+    (0..N).map(|i| {
+        let d = (r.min[i] - p[i]).max(p[i] - (r.max[i] - 1)).max(0) as f64;
+        d * d
+    }).sum::<f64>().sqrt()
 }
 
 impl Point for [i32; 2] {
-    fn to_cube(&self, size: u32) -> [Self; 2] {
-        [[self[0], self[1]], [self[0] + size as i32, self[1] + size as i32]]
+    fn to_cube(&self, size: u32) -> Region<Self> {
+        Region::new([self[0], self[1]], [self[0] + size as i32, self[1] + size as i32])
+    }
+
+    fn overlap_rect(a: &Region<Self>, other: &Region<Self>) -> bool {
+        box_intersect(a, other).is_some()
+    }
+
+    fn intersect(a: &Region<Self>, other: &Region<Self>) -> Option<Region<Self>> {
+        box_intersect(a, other)
+    }
+
+    fn bounding_box(a: &Region<Self>, other: &Region<Self>) -> Region<Self> {
+        box_bounding_box(a, other)
+    }
+
+    fn area(r: &Region<Self>) -> usize {
+        box_area(r)
     }
 
-    fn overlap_rect(a: &[Self; 2], other: &[Self; 2]) -> bool {
-        (a[0][0] >= other[0][0] && a[0][0] < other[1][0]) ||
-        (a[1][0] >= other[0][0] && a[1][0] < other[1][0]) ||
-        (a[0][1] >= other[0][1] && a[0][1] < other[1][1]) ||
-        (a[1][1] >= other[0][1] && a[1][1] < other[1][1])
+    fn split(r: &Region<Self>) -> Option<[Region<Self>; 2]> {
+        box_split(r)
     }
 
-    fn expand(r: &[Self; 2], margin: u32) -> [Self; 2] {
-        [[r[0][0] - margin as i32, r[0][1] - margin as i32], [r[1][0] + margin as i32, r[1][1] + margin as i32]]
+    fn difference(a: &Region<Self>, other: &Region<Self>) -> Vec<Region<Self>> {
+        box_difference(a, other)
     }
 
-    fn contained(&self, r: &[Self; 2]) -> bool {
-        (self[0] >= r[0][0] && self[0] < r[1][0]) ||
-        (self[1] >= r[0][1] && self[1] < r[1][1])
+    fn expand(r: &Region<Self>, margin: u32) -> Region<Self> {
+        Region::new([r.min[0] - margin as i32, r.min[1] - margin as i32], [r.max[0] + margin as i32, r.max[1] + margin as i32])
+    }
+
+    fn contained(&self, r: &Region<Self>) -> bool {
+        (self[0] >= r.min[0] && self[0] < r.max[0]) &&
+        (self[1] >= r.min[1] && self[1] < r.max[1])
     }
 
     fn max_unrolled_index(chunk_size: u32) -> usize {
@@ -41,28 +191,27 @@ impl Point for [i32; 2] {
     }
 
     fn chunk_index(&self, chunk_size: u32) -> (Self, usize) {
-        let x = (self[0] / chunk_size as i32) * chunk_size as i32;
+        let x = self[0].div_euclid(chunk_size as i32) * chunk_size as i32;
         let x_r = self[0] - x;
-        let y = (self[1] / chunk_size as i32) * chunk_size as i32;
+        let y = self[1].div_euclid(chunk_size as i32) * chunk_size as i32;
         let y_r = self[1] - y;
         ([x, y], y_r as usize * chunk_size as usize + x_r as usize)
     }
 
-    fn chunks_in_region(r: &[Self; 2], chunk_size: u32) -> Vec<[Self; 2]> {
-        let low_x = (r[0][0] as f64 / chunk_size as f64).floor() as i32 * chunk_size as i32;
-        let mut y = (r[0][1] as f64 / chunk_size as f64).floor() as i32 * chunk_size as i32;
+    fn chunks_in_region(r: &Region<Self>, chunk_size: u32) -> Vec<Region<Self>> {
+        let low_x = (r.min[0] as f64 / chunk_size as f64).floor() as i32 * chunk_size as i32;
+        let mut y = (r.min[1] as f64 / chunk_size as f64).floor() as i32 * chunk_size as i32;
         let mut x = low_x;
 
         //FIXME: I'd really rather just return the iterator but I'm not sure how to make the types
         //work
         from_fn(move || {
-            if x < r[1][0] || y < r[1][1] {
-                let p = Some([[x, y], [x+chunk_size as i32, y+chunk_size as i32]]);
-                if x > r[1][0] {
+            if x < r.max[0] && y < r.max[1] {
+                let p = Some([x, y].to_cube(chunk_size));
+                x += chunk_size as i32;
+                if x >= r.max[0] {
                     x = low_x;
                     y += chunk_size as i32;
-                } else {
-                    x += chunk_size as i32;
                 }
                 p
             } else {
@@ -71,14 +220,22 @@ impl Point for [i32; 2] {
         }).collect()
     }
 
-    fn points_in_region(r: &[Self; 2]) -> Vec<Self> {
-        (r[0][0]..r[1][0]).map(move |x| (r[0][1]..r[1][1]).map(move |y| [x, y])).flatten().collect()
+    fn points_in_region(r: &Region<Self>) -> Vec<Self> {
+        (r.min[0]..r.max[0]).flat_map(move |x| (r.min[1]..r.max[1]).map(move |y| [x, y])).collect()
     }
 
     fn neighboors(&self) -> Vec<Self> {
         [(-1, 0), (1, 0), (0, -1), (0, 1)].iter().map(|(dx, dy)| [self[0]+dx, self[1]+dy]).collect()
     }
 
+    fn distance_to_region(&self, r: &Region<Self>) -> f64 {
+        box_distance(self, r)
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        [self[0] + other[0], self[1] + other[1]]
+    }
+
     fn mul(&self, m: i32) -> Self {
         [self[0] * m, self[1] * m]
     }
@@ -86,4 +243,161 @@ impl Point for [i32; 2] {
     fn div(&self, m: i32) -> Self {
         [self[0] / m, self[1] / m]
     }
+    fn hash_region<H: Hasher>(r: &Region<Self>, state: &mut H) {
+        box_hash(r, state);
+    }
+}
+
+impl Point for [i32; 3] {
+    fn to_cube(&self, size: u32) -> Region<Self> {
+        let size = size as i32;
+        Region::new(*self, [self[0] + size, self[1] + size, self[2] + size])
+    }
+
+    fn overlap_rect(a: &Region<Self>, other: &Region<Self>) -> bool {
+        box_intersect(a, other).is_some()
+    }
+
+    fn intersect(a: &Region<Self>, other: &Region<Self>) -> Option<Region<Self>> {
+        box_intersect(a, other)
+    }
+
+    fn bounding_box(a: &Region<Self>, other: &Region<Self>) -> Region<Self> {
+        box_bounding_box(a, other)
+    }
+
+    fn area(r: &Region<Self>) -> usize {
+        box_area(r)
+    }
+
+    fn split(r: &Region<Self>) -> Option<[Region<Self>; 2]> {
+        box_split(r)
+    }
+
+    fn difference(a: &Region<Self>, other: &Region<Self>) -> Vec<Region<Self>> {
+        box_difference(a, other)
+    }
+
+    fn expand(r: &Region<Self>, margin: u32) -> Region<Self> {
+        let m = margin as i32;
+        Region::new([r.min[0] - m, r.min[1] - m, r.min[2] - m], [r.max[0] + m, r.max[1] + m, r.max[2] + m])
+    }
+
+    fn contained(&self, r: &Region<Self>) -> bool {
+        (0..3).all(|i| self[i] >= r.min[i] && self[i] < r.max[i])
+    }
+
+    fn max_unrolled_index(chunk_size: u32) -> usize {
+        chunk_size as usize * chunk_size as usize * chunk_size as usize
+    }
+
+    fn chunk_index(&self, chunk_size: u32) -> (Self, usize) {
+        let size = chunk_size as i32;
+        let c = [
+            self[0].div_euclid(size) * size,
+            self[1].div_euclid(size) * size,
+            self[2].div_euclid(size) * size,
+        ];
+        let size = chunk_size as usize;
+        let i = ((self[2] - c[2]) as usize * size + (self[1] - c[1]) as usize) * size + (self[0] - c[0]) as usize;
+        (c, i)
+    }
+
+    fn chunks_in_region(r: &Region<Self>, chunk_size: u32) -> Vec<Region<Self>> {
+        let size = chunk_size as i32;
+        let low = [r.min[0].div_euclid(size) * size, r.min[1].div_euclid(size) * size, r.min[2].div_euclid(size) * size];
+        let mut chunks = vec![];
+        for z in (low[2]..r.max[2]).step_by(chunk_size as usize) {
+            for y in (low[1]..r.max[1]).step_by(chunk_size as usize) {
+                for x in (low[0]..r.max[0]).step_by(chunk_size as usize) {
+                    chunks.push([x, y, z].to_cube(chunk_size));
+                }
+            }
+        }
+        chunks
+    }
+
+    fn points_in_region(r: &Region<Self>) -> Vec<Self> {
+        let mut points = vec![];
+        for x in r.min[0]..r.max[0] {
+            for y in r.min[1]..r.max[1] {
+                for z in r.min[2]..r.max[2] {
+                    points.push([x, y, z]);
+                }
+            }
+        }
+        points
+    }
+
+    fn neighboors(&self) -> Vec<Self> {
+        [(-1, 0, 0), (1, 0, 0), (0, -1, 0), (0, 1, 0), (0, 0, -1), (0, 0, 1)].iter()
+            .map(|(dx, dy, dz)| [self[0]+dx, self[1]+dy, self[2]+dz])
+            .collect()
+    }
+
+    fn distance_to_region(&self, r: &Region<Self>) -> f64 {
+        box_distance(self, r)
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        [self[0] + other[0], self[1] + other[1], self[2] + other[2]]
+    }
+
+    fn mul(&self, m: i32) -> Self {
+        [self[0] * m, self[1] * m, self[2] * m]
+    }
+
+    fn div(&self, m: i32) -> Self {
+        [self[0] / m, self[1] / m, self[2] / m]
+    }
+    fn hash_region<H: Hasher>(r: &Region<Self>, state: &mut H) {
+        box_hash(r, state);
+    }
+}
+
+// The full 26 cell neighboorhood, including edge and corner diagonals
+pub fn neighboors_26(p: &[i32; 3]) -> Vec<[i32; 3]> {
+    let mut neighboors = Vec::with_capacity(26);
+    for dx in -1..=1 {
+        for dy in -1..=1 {
+            for dz in -1..=1 {
+                if (dx, dy, dz) != (0, 0, 0) {
+                    neighboors.push([p[0] + dx, p[1] + dy, p[2] + dz]);
+                }
+            }
+        }
+    }
+    neighboors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn voxel_chunks_cover_region() {
+        let r = Region::new([-3, -5, -1], [6, 4, 9]);
+        let chunks = <[i32; 3]>::chunks_in_region(&r, 4);
+        assert_eq!(chunks.len(), 3 * 3 * 4);
+        let points = <[i32; 3]>::points_in_region(&r);
+        assert_eq!(points.len(), 9 * 9 * 10);
+        let mut seen = std::collections::HashSet::new();
+        for p in &points {
+            let (origin, i) = p.chunk_index(4);
+            assert!(i < <[i32; 3]>::max_unrolled_index(4));
+            assert!(seen.insert((origin, i)));
+            let chunk = origin.to_cube(4);
+            assert!(p.contained(&chunk));
+            assert!(chunks.contains(&chunk));
+        }
+    }
+
+    #[test]
+    fn voxel_neighboors() {
+        let p = [1, 2, 3];
+        assert_eq!(p.neighboors().len(), 6);
+        assert_eq!(neighboors_26(&p).len(), 26);
+        let around = <[i32; 3]>::expand(&p.to_cube(1), 1);
+        assert!(neighboors_26(&p).iter().all(|n| n.contained(&around) && !n.contained(&p.to_cube(1))));
+    }
 }
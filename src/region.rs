@@ -0,0 +1,424 @@
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::iter::FromIterator;
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+use crate::point::Point;
+
+// A half open box of points, `min` is inside the region and `max` is just outside it. What box
+// means depends on the point type, see the `Point` implementations.
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Region<P> {
+    pub min: P,
+    pub max: P,
+}
+
+impl<P: Point> Region<P> {
+    pub fn new(min: P, max: P) -> Self {
+        Self {
+            min,
+            max,
+        }
+    }
+
+    pub fn contains(&self, p: &P) -> bool {
+        p.contained(self)
+    }
+
+    pub fn overlaps(&self, other: &Self) -> bool {
+        P::overlap_rect(self, other)
+    }
+
+    // None if the regions don't overlap
+    pub fn intersection(&self, other: &Self) -> Option<Self> {
+        P::intersect(self, other)
+    }
+
+    // Disjoint regions covering the points of this one which are not in `other`
+    pub fn difference(&self, other: &Self) -> Vec<Self> {
+        P::difference(self, other)
+    }
+
+    // The smallest region containing both
+    pub fn bounding_box(&self, other: &Self) -> Self {
+        P::bounding_box(self, other)
+    }
+
+    pub fn area(&self) -> usize {
+        P::area(self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.area() == 0
+    }
+
+    pub fn expand(&self, margin: u32) -> Self {
+        P::expand(self, margin)
+    }
+
+    pub fn points(&self) -> Vec<P> {
+        P::points_in_region(self)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=P> {
+        self.points().into_iter()
+    }
+
+    // Two non-empty halves which together cover the region, None if it's too small to split
+    pub fn split(&self) -> Option<[Self; 2]> {
+        P::split(self)
+    }
+
+    pub fn chunks(&self, chunk_size: u32) -> Vec<Self> {
+        P::chunks_in_region(self, chunk_size)
+    }
+
+    // The smallest region made up of whole chunks which covers this one
+    pub fn align_to_chunks(&self, chunk_size: u32) -> Self {
+        let mut chunks = self.chunks(chunk_size).into_iter();
+        match chunks.next() {
+            Some(first) => chunks.fold(first, |aligned, chunk| aligned.bounding_box(&chunk)),
+            None => self.clone(),
+        }
+    }
+}
+
+impl<P: Point> PartialEq for Region<P> {
+    fn eq(&self, other: &Self) -> bool {
+        P::same_region(self, other)
+    }
+}
+
+impl<P: Point> Eq for Region<P> {}
+
+impl<P: Point> Hash for Region<P> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        P::hash_region(self, state);
+    }
+}
+
+impl<P> From<[P; 2]> for Region<P> {
+    fn from(r: [P; 2]) -> Self {
+        let [min, max] = r;
+        Self {
+            min,
+            max,
+        }
+    }
+}
+
+// A union of regions, kept as a list of non-empty regions which don't overlap so every point is
+// covered at most once. Used for areas which aren't a single box, like an L-shaped corridor or a
+// view minus the chunks that are already loaded.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RegionSet<P> {
+    regions: Vec<Region<P>>,
+}
+
+impl<P> Default for RegionSet<P> {
+    fn default() -> Self {
+        Self {
+            regions: vec![],
+        }
+    }
+}
+
+impl<P: Point> RegionSet<P> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn regions(&self) -> &[Region<P>] {
+        &self.regions
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    pub fn area(&self) -> usize {
+        self.regions.iter().map(Region::area).sum()
+    }
+
+    pub fn contains(&self, p: &P) -> bool {
+        self.regions.iter().any(|r| r.contains(p))
+    }
+
+    pub fn overlaps(&self, r: &Region<P>) -> bool {
+        self.regions.iter().any(|region| region.overlaps(r))
+    }
+
+    pub fn overlaps_set(&self, other: &Self) -> bool {
+        other.regions.iter().any(|r| self.overlaps(r))
+    }
+
+    pub fn bounding_box(&self) -> Option<Region<P>> {
+        let mut regions = self.regions.iter();
+        let first = regions.next()?.clone();
+        Some(regions.fold(first, |bounds, r| bounds.bounding_box(r)))
+    }
+
+    // Adds whatever part of the region isn't already covered
+    pub fn insert(&mut self, r: &Region<P>) {
+        let mut pieces = vec![r.clone()];
+        for existing in &self.regions {
+            pieces = pieces.iter().flat_map(|piece| piece.difference(existing)).collect();
+            if pieces.is_empty() {
+                return;
+            }
+        }
+        self.regions.extend(pieces.into_iter().filter(|piece| !piece.is_empty()));
+    }
+
+    // For a region known not to overlap the set, which saves `insert` checking it against every
+    // region already in it
+    pub(crate) fn push_disjoint(&mut self, r: Region<P>) {
+        if !r.is_empty() {
+            self.regions.push(r);
+        }
+    }
+
+    pub fn remove(&mut self, r: &Region<P>) {
+        if self.overlaps(r) {
+            self.regions = self.regions.iter().flat_map(|region| region.difference(r)).collect();
+        }
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        let mut union = self.clone();
+        for r in &other.regions {
+            union.insert(r);
+        }
+        union
+    }
+
+    pub fn difference(&self, other: &Self) -> Self {
+        let mut difference = self.clone();
+        for r in &other.regions {
+            difference.remove(r);
+        }
+        difference
+    }
+
+    // Built from differences rather than `Region::intersection` since intersecting two regions
+    // can leave more than one piece on some point types
+    pub fn intersection(&self, other: &Self) -> Self {
+        self.difference(&self.difference(other))
+    }
+
+    pub fn points(&self) -> Vec<P> {
+        self.regions.iter().flat_map(Region::points).collect()
+    }
+
+    // Every chunk touching the set, each listed once
+    pub fn chunks(&self, chunk_size: u32) -> Vec<Region<P>> {
+        let mut seen = HashSet::new();
+        self.regions.iter()
+            .flat_map(|r| r.chunks(chunk_size))
+            .filter(|chunk| seen.insert(chunk.clone()))
+            .collect()
+    }
+}
+
+// Sets are equal when they cover the same points, however those are split into regions
+impl<P: Point> PartialEq for RegionSet<P> {
+    fn eq(&self, other: &Self) -> bool {
+        self.difference(other).is_empty() && other.difference(self).is_empty()
+    }
+}
+
+impl<P: Point> Eq for RegionSet<P> {}
+
+impl<P: Point> From<Region<P>> for RegionSet<P> {
+    fn from(r: Region<P>) -> Self {
+        std::iter::once(r).collect()
+    }
+}
+
+impl<P: Point> From<&Region<P>> for RegionSet<P> {
+    fn from(r: &Region<P>) -> Self {
+        Self::from(r.clone())
+    }
+}
+
+impl<P: Point> From<&[Region<P>]> for RegionSet<P> {
+    fn from(regions: &[Region<P>]) -> Self {
+        regions.iter().cloned().collect()
+    }
+}
+
+impl<P: Point> From<Vec<Region<P>>> for RegionSet<P> {
+    fn from(regions: Vec<Region<P>>) -> Self {
+        regions.into_iter().collect()
+    }
+}
+
+impl<P: Point> FromIterator<Region<P>> for RegionSet<P> {
+    fn from_iter<I: IntoIterator<Item=Region<P>>>(regions: I) -> Self {
+        let mut set = Self::new();
+        for r in regions {
+            set.insert(&r);
+        }
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::{
+        hex::Hex,
+        wrapping::Wrapping,
+    };
+
+    // Every box with corners in `coords` on each axis, including empty and inverted ones
+    fn boxes(coords: &[i32]) -> Vec<Region<[i32; 2]>> {
+        let mut boxes = vec![];
+        for &x0 in coords {
+            for &x1 in coords {
+                for &y0 in coords {
+                    for &y1 in coords {
+                        boxes.push(Region::new([x0, y0], [x1, y1]));
+                    }
+                }
+            }
+        }
+        boxes
+    }
+
+    fn members<P: Point>(r: &Region<P>, domain: &[P]) -> HashSet<P> {
+        domain.iter().filter(|p| r.contains(p)).cloned().collect()
+    }
+
+    // `exact` is false for point types where an intersection can be two disjoint pieces and only
+    // one of them is returned
+    fn check<P: Point>(regions: &[Region<P>], domain: &[P], chunk_size: u32, exact: bool) {
+        let sets: Vec<_> = regions.iter().map(|r| members(r, domain)).collect();
+        for (a, in_a) in regions.iter().zip(&sets) {
+            let points: Vec<_> = a.points();
+            assert_eq!(points.len(), a.area(), "{:?}", a);
+            assert_eq!(&points.iter().cloned().collect::<HashSet<_>>(), in_a, "{:?}", a);
+            assert_eq!(a.is_empty(), in_a.is_empty(), "{:?}", a);
+
+            if let Some([low, high]) = a.split() {
+                let (in_low, in_high) = (members(&low, domain), members(&high, domain));
+                assert!(!in_low.is_empty() && !in_high.is_empty(), "{:?}", a);
+                assert!(in_low.is_disjoint(&in_high), "{:?}", a);
+                assert_eq!(&(&in_low | &in_high), in_a, "{:?}", a);
+            } else {
+                assert!(a.area() < 2, "{:?}", a);
+            }
+
+            let aligned = members(&a.align_to_chunks(chunk_size), domain);
+            assert!(in_a.is_subset(&aligned), "{:?}", a);
+            for chunk in a.chunks(chunk_size) {
+                assert_eq!(chunk.min.chunk_index(chunk_size).1, 0, "{:?}", a);
+                assert!(members(&chunk, domain).is_subset(&aligned), "{:?}", a);
+            }
+
+            for (b, in_b) in regions.iter().zip(&sets) {
+                let shared = in_a & in_b;
+                assert_eq!(a.overlaps(b), !shared.is_empty(), "{:?} {:?}", a, b);
+                match a.intersection(b) {
+                    Some(i) if exact => assert_eq!(members(&i, domain), shared, "{:?} {:?}", a, b),
+                    Some(i) => {
+                        let in_i = members(&i, domain);
+                        assert!(!in_i.is_empty() && in_i.is_subset(&shared), "{:?} {:?}", a, b);
+                    }
+                    None => assert!(shared.is_empty(), "{:?} {:?}", a, b),
+                }
+                let mut rest = HashSet::new();
+                for piece in a.difference(b) {
+                    let in_piece = members(&piece, domain);
+                    assert!(!in_piece.is_empty() && rest.is_disjoint(&in_piece), "{:?} {:?}", a, b);
+                    rest.extend(in_piece);
+                }
+                assert_eq!(rest, in_a - in_b, "{:?} {:?}", a, b);
+                let bounds = members(&a.bounding_box(b), domain);
+                assert!(in_a.is_subset(&bounds) && in_b.is_subset(&bounds), "{:?} {:?}", a, b);
+            }
+        }
+    }
+
+    fn set_members<P: Point>(set: &RegionSet<P>, domain: &[P]) -> HashSet<P> {
+        let mut in_set = HashSet::new();
+        for r in set.regions() {
+            let in_r = members(r, domain);
+            assert!(!in_r.is_empty() && in_set.is_disjoint(&in_r), "{:?}", set);
+            in_set.extend(in_r);
+        }
+        assert_eq!(in_set.len(), set.area(), "{:?}", set);
+        in_set
+    }
+
+    // Sets built from consecutive runs of three regions, so they overlap each other in most ways
+    fn check_sets<P: Point>(regions: &[Region<P>], domain: &[P]) {
+        let sets: Vec<RegionSet<P>> = regions.chunks(3).map(RegionSet::from).collect();
+        let in_sets: Vec<_> = sets.iter().map(|set| set_members(set, domain)).collect();
+        for (set, run) in sets.iter().zip(regions.chunks(3)) {
+            let covered: HashSet<_> = run.iter().flat_map(|r| members(r, domain)).collect();
+            assert_eq!(set_members(set, domain), covered, "{:?}", run);
+        }
+        for (a, in_a) in sets.iter().zip(&in_sets) {
+            for (b, in_b) in sets.iter().zip(&in_sets) {
+                assert_eq!(set_members(&a.union(b), domain), in_a | in_b, "{:?} {:?}", a, b);
+                assert_eq!(set_members(&a.difference(b), domain), in_a - in_b, "{:?} {:?}", a, b);
+                assert_eq!(set_members(&a.intersection(b), domain), in_a & in_b, "{:?} {:?}", a, b);
+                assert_eq!(a.overlaps_set(b), !in_a.is_disjoint(in_b), "{:?} {:?}", a, b);
+                assert_eq!(a == b, in_a == in_b, "{:?} {:?}", a, b);
+                assert_eq!(a.union(b), b.union(a), "{:?} {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn grid_set_operations() {
+        let coords = [-1, 0, 1, 2, 3];
+        let domain: Vec<_> = (-2..5).flat_map(|x| (-2..5).map(move |y| [x, y])).collect();
+        check(&boxes(&coords), &domain, 2, true);
+        check_sets(&boxes(&[-1, 0, 1, 3]), &domain);
+    }
+
+    #[test]
+    fn voxel_set_operations() {
+        let coords = [-1, 0, 2];
+        let mut regions = vec![];
+        for r in boxes(&coords) {
+            for &z0 in &coords {
+                for &z1 in &coords {
+                    regions.push(Region::new([r.min[0], r.min[1], z0], [r.max[0], r.max[1], z1]));
+                }
+            }
+        }
+        let domain: Vec<_> = (-1..3)
+            .flat_map(|x| (-1..3).flat_map(move |y| (-1..3).map(move |z| [x, y, z])))
+            .collect();
+        check(&regions, &domain, 2, true);
+    }
+
+    #[test]
+    fn hex_set_operations() {
+        let regions: Vec<_> = boxes(&[-1, 0, 1, 3]).into_iter()
+            .map(|r| Region::new(Hex::from_offset(r.min[0], r.min[1]), Hex::from_offset(r.max[0], r.max[1])))
+            .collect();
+        let domain: Vec<_> = (-2..5).flat_map(|col| (-2..5).map(move |row| Hex::from_offset(col, row))).collect();
+        check(&regions, &domain, 2, true);
+    }
+
+    #[test]
+    fn wrapping_set_operations() {
+        type Ring = Wrapping<4, 0>;
+        let regions: Vec<_> = boxes(&[-1, 0, 2, 5]).into_iter()
+            .map(|r| Region::new(Ring::new(r.min[0], r.min[1]), Ring::new(r.max[0], r.max[1])))
+            .collect();
+        let domain: Vec<_> = (0..4).flat_map(|x| (-1..5).map(move |y| Ring::new(x, y))).collect();
+        check(&regions, &domain, 2, false);
+        check_sets(&regions, &domain);
+    }
+}
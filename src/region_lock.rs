@@ -1,22 +1,24 @@
-use std::collections::{HashMap, HashSet};
-use std::sync::Mutex;
+use std::collections::HashMap;
+use std::sync::{Mutex, Condvar};
+use std::time::{Duration, Instant};
 
-use parking_lot_core::{park, unpark_one, DEFAULT_PARK_TOKEN, DEFAULT_UNPARK_TOKEN};
-
-use crate::point::Point;
+use crate::{
+    point::Point,
+    region::RegionSet,
+};
 
 #[derive(Hash, PartialEq, Eq, Copy, Clone)]
 pub struct LockKey(usize, bool);
 
 struct Inner<Point> {
-    read: HashMap<LockKey, (Vec<[Point; 2]>, Vec<LockKey>)>,
-    write: HashMap<LockKey, (Vec<[Point; 2]>, Vec<LockKey>)>,
+    read: HashMap<LockKey, RegionSet<Point>>,
+    write: HashMap<LockKey, RegionSet<Point>>,
     lock_id: usize,
-    pending: HashSet<LockKey>,
 }
 
 pub struct Lock<Point> {
     lock: Mutex<Inner<Point>>,
+    released: Condvar,
 }
 
 impl<P: Point> Lock<P> {
@@ -26,71 +28,50 @@ impl<P: Point> Lock<P> {
                 read: HashMap::new(),
                 write: HashMap::new(),
                 lock_id: 0,
-                pending: HashSet::new(),
             }),
+            released: Condvar::new(),
         }
     }
 
-    pub fn lock_region(&self, regions: &[[P; 2]], is_write: bool, blocking: bool) -> Option<Guard<P>> {
-        let mut conflict = true;
-        let mut key = LockKey(0, is_write);
-        while conflict {
-            conflict = false;
-            {
-                let mut inner = self.lock.lock().unwrap();
-                if inner.read.is_empty() && inner.write.is_empty() && inner.pending.is_empty() {
-                    inner.lock_id = 0;
-                }
-                key = LockKey(inner.lock_id, is_write);
-                inner.lock_id += 1;
-                'outer: for (_, (lock_regions, queue)) in &mut inner.write {
-                    for region in regions {
-                        for lock_region in lock_regions.iter() {
-                            if Point::overlap_rect(region, lock_region) {
-                                conflict = true;
-                                queue.push(key);
-                                break 'outer;
-                            }
-                        }
-                    }
-                }
-                if is_write {
-                    'outer2: for (_, (lock_regions, queue)) in &mut inner.read {
-                        for region in regions {
-                            for lock_region in lock_regions.iter() {
-                                if Point::overlap_rect(region, lock_region) {
-                                    conflict = true;
-                                    queue.push(key);
-                                    break 'outer2;
-                                }
-                            }
-                        }
-                    }
-                }
-                if !conflict {
-                    if is_write {
-                        inner.write.insert(key, (regions.to_vec(), vec![]));
-                    } else {
-                        inner.read.insert(key, (regions.to_vec(), vec![]));
-                    }
-                } else {
-                    if blocking {
-                        inner.pending.insert(key);
-                        unsafe {
-                            park(
-                                key.0,
-                                || true,
-                                || {},
-                                |_,_| {},
-                                DEFAULT_PARK_TOKEN,
-                                None,
-                            );
-                        }
-                    } else {
+    // Only the points in the set are locked, so an L-shaped set doesn't block the rest of its
+    // bounding box
+    pub fn lock_region(&self, regions: &RegionSet<P>, is_write: bool, blocking: bool) -> Option<Guard<'_, P>> {
+        self.lock_region_timeout(regions, is_write, if blocking { None } else { Some(Duration::from_secs(0)) })
+    }
+
+    // Gives up once `timeout` has passed without the region becoming free, None waits forever
+    pub fn lock_region_timeout(&self, regions: &RegionSet<P>, is_write: bool, timeout: Option<Duration>) -> Option<Guard<'_, P>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut inner = self.lock.lock().unwrap();
+        loop {
+            let overlaps = |locked: &RegionSet<P>| regions.overlaps_set(locked);
+            let conflict = inner.write.values().any(overlaps) ||
+                (is_write && inner.read.values().any(overlaps));
+            if !conflict {
+                break;
+            }
+            // Wait for some other guard to be dropped and then check again
+            inner = match deadline {
+                None => self.released.wait(inner).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
                         return None;
                     }
+                    self.released.wait_timeout(inner, deadline - now).unwrap().0
                 }
-            }
+            };
+        }
+
+        if inner.read.is_empty() && inner.write.is_empty() {
+            inner.lock_id = 0;
+        }
+        let key = LockKey(inner.lock_id, is_write);
+        inner.lock_id += 1;
+        if is_write {
+            inner.write.insert(key, regions.clone());
+        } else {
+            inner.read.insert(key, regions.clone());
         }
 
         Some(Guard {
@@ -99,38 +80,38 @@ impl<P: Point> Lock<P> {
         })
     }
 
-    pub fn read_region(&self, regions: &[[P; 2]]) -> Guard<P> {
+    pub fn read_region(&self, regions: &RegionSet<P>) -> Guard<'_, P> {
         self.lock_region(regions, false, true).unwrap()
     }
 
-    pub fn try_read_region(&self, regions: &[[P; 2]]) -> Option<Guard<P>> {
+    pub fn try_read_region(&self, regions: &RegionSet<P>) -> Option<Guard<'_, P>> {
         self.lock_region(regions, false, false)
     }
 
-    pub fn write_region(&self, regions: &[[P; 2]]) -> Guard<P> {
+    pub fn read_region_timeout(&self, regions: &RegionSet<P>, timeout: Duration) -> Option<Guard<'_, P>> {
+        self.lock_region_timeout(regions, false, Some(timeout))
+    }
+
+    pub fn write_region(&self, regions: &RegionSet<P>) -> Guard<'_, P> {
         self.lock_region(regions, true, true).unwrap()
     }
 
-    pub fn try_write_region(&self, regions: &[[P; 2]]) -> Option<Guard<P>> {
+    pub fn try_write_region(&self, regions: &RegionSet<P>) -> Option<Guard<'_, P>> {
         self.lock_region(regions, true, false)
     }
 
+    pub fn write_region_timeout(&self, regions: &RegionSet<P>, timeout: Duration) -> Option<Guard<'_, P>> {
+        self.lock_region_timeout(regions, true, Some(timeout))
+    }
+
     fn unlock_region(&self, key: &LockKey) {
         let mut inner = self.lock.lock().unwrap();
-        let queue;
         if key.1 {
-            queue = inner.write.remove(key).unwrap().1;
+            inner.write.remove(key);
         } else {
-            queue = inner.read.remove(key).unwrap().1;
-        }
-        for other_lock in queue {
-            unsafe {
-                unpark_one(
-                    other_lock.0,
-                    |_| DEFAULT_UNPARK_TOKEN,
-                );
-            }
+            inner.read.remove(key);
         }
+        self.released.notify_all();
     }
 }
 
@@ -149,42 +130,78 @@ impl<'a, P: Point> Drop for Guard<'a, P> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::region::Region;
 
     #[test]
     fn read_lock_region() {
         let mut lock = Lock::new();
-        let _read_key = lock.read_region(&[[[0, 0], [100, 100]]]);
-        assert!(lock.try_read_region(&[[[200, 200], [250, 250]]]).is_some());
-        assert!(lock.try_read_region(&[[[20, 20], [25, 25]]]).is_some());
-        assert!(lock.try_write_region(&[[[20, 20], [25, 25]]]).is_none());
+        let _read_key = lock.read_region(&Region::new([0, 0], [100, 100]).into());
+        assert!(lock.try_read_region(&Region::new([200, 200], [250, 250]).into()).is_some());
+        assert!(lock.try_read_region(&Region::new([20, 20], [25, 25]).into()).is_some());
+        assert!(lock.try_write_region(&Region::new([20, 20], [25, 25]).into()).is_none());
     }
 
     #[test]
     fn write_lock_region() {
         let mut lock = Lock::new();
-        let _write_key = lock.write_region(&[[[0, 0], [100, 100]]]);
-        assert!(lock.try_read_region(&[[[200, 200], [250, 250]]]).is_some());
-        assert!(lock.try_read_region(&[[[20, 20], [25, 25]]]).is_none());
-        assert!(lock.try_write_region(&[[[20, 20], [25, 25]]]).is_none());
+        let _write_key = lock.write_region(&Region::new([0, 0], [100, 100]).into());
+        assert!(lock.try_read_region(&Region::new([200, 200], [250, 250]).into()).is_some());
+        assert!(lock.try_read_region(&Region::new([20, 20], [25, 25]).into()).is_none());
+        assert!(lock.try_write_region(&Region::new([20, 20], [25, 25]).into()).is_none());
     }
 
     #[test]
     fn unlock_read() {
         let mut lock = Lock::new();
         {
-            let read_key = lock.read_region(&[[[0, 0], [100, 100]]]);
-            assert!(lock.try_write_region(&[[[20, 20], [25, 25]]]).is_none());
+            let read_key = lock.read_region(&Region::new([0, 0], [100, 100]).into());
+            assert!(lock.try_write_region(&Region::new([20, 20], [25, 25]).into()).is_none());
         }
-        assert!(lock.try_write_region(&[[[20, 20], [25, 25]]]).is_some());
+        assert!(lock.try_write_region(&Region::new([20, 20], [25, 25]).into()).is_some());
     }
 
     #[test]
     fn unlock_write() {
         let mut lock = Lock::new();
         {
-            let write_key = lock.write_region(&[[[0, 0], [100, 100]]]);
-            assert!(lock.try_read_region(&[[[20, 20], [25, 25]]]).is_none());
+            let write_key = lock.write_region(&Region::new([0, 0], [100, 100]).into());
+            assert!(lock.try_read_region(&Region::new([20, 20], [25, 25]).into()).is_none());
         }
-        assert!(lock.try_read_region(&[[[20, 20], [25, 25]]]).is_some());
+        assert!(lock.try_read_region(&Region::new([20, 20], [25, 25]).into()).is_some());
+    }
+
+    #[test]
+    fn lock_timeout() {
+        let lock = Lock::new();
+        let _write_key = lock.write_region(&Region::new([0, 0], [100, 100]).into());
+        assert!(lock.read_region_timeout(&Region::new([20, 20], [25, 25]).into(), Duration::from_millis(10)).is_none());
+        assert!(lock.write_region_timeout(&Region::new([200, 200], [250, 250]).into(), Duration::from_millis(10)).is_some());
+    }
+
+    #[test]
+    fn lock_l_shape() {
+        let lock = Lock::new();
+        let corridor: RegionSet<_> = vec![Region::new([0, 0], [10, 2]), Region::new([0, 0], [2, 10])].into();
+        let _write_key = lock.write_region(&corridor);
+        assert!(lock.try_read_region(&Region::new([5, 5], [10, 10]).into()).is_some());
+        assert!(lock.try_read_region(&Region::new([1, 5], [10, 10]).into()).is_none());
+    }
+
+    #[test]
+    fn blocked_lock_wakes_on_unlock() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let lock = Arc::new(Lock::new());
+        let write_key = lock.write_region(&Region::new([0, 0], [100, 100]).into());
+        let waiter = {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                let _read_key = lock.read_region(&Region::new([20, 20], [25, 25]).into());
+            })
+        };
+        drop(write_key);
+        waiter.join().unwrap();
+        assert!(lock.try_write_region(&Region::new([20, 20], [25, 25]).into()).is_some());
     }
 }
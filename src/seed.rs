@@ -0,0 +1,81 @@
+use std::hash::{Hash, Hasher};
+
+// FNV-1a, since std's DefaultHasher makes no promises about its algorithm across releases and
+// derived seeds need to reproduce the same world everywhere. Integers are encoded little-endian.
+// std hashes slices and arrays of integers as their in-memory bytes though, so the crate's point
+// types and regions hash their coordinates one at a time, and salts shouldn't contain raw arrays.
+struct StableHasher(u64);
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= u64::from(*b);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_i16(&mut self, i: i16) {
+        self.write_u16(i as u16);
+    }
+
+    fn write_i32(&mut self, i: i32) {
+        self.write_u32(i as u32);
+    }
+
+    fn write_i64(&mut self, i: i64) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_u64(i as u64);
+    }
+}
+
+pub fn derive_seed<H: Hash>(seed: u64, salt: &H) -> u64 {
+    let mut hasher = StableHasher(0xcbf2_9ce4_8422_2325);
+    hasher.write_u64(seed);
+    salt.hash(&mut hasher);
+
+    // splitmix64 finalizer so that nearby salts give unrelated seeds
+    let mut z = hasher.finish();
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        region::Region,
+        wrapping::Wrapping,
+    };
+
+    #[test]
+    fn regions_hash_their_coordinates() {
+        let seed = derive_seed(7, &Region::new([1, -2], [3, 4]));
+        assert_eq!(seed, derive_seed(7, &(1i32, -2i32, 3i32, 4i32)));
+        assert_eq!(derive_seed(7, &Region::new([1, -2, 0], [3, 4, 5])), derive_seed(7, &(1i32, -2i32, 0i32, 3i32, 4i32, 5i32)));
+        assert_eq!(derive_seed(7, &Wrapping::<8, 0>::new(9, -1)), derive_seed(7, &(1i32, -1i32)));
+    }
+}
@@ -1,60 +1,401 @@
 use std::collections::HashMap;
+use std::marker::PhantomData;
 
-use crate::point::Point;
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
 
-pub struct SparseMap<P, T> {
+use crate::{
+    error::{self, Error},
+    point::Point,
+    region::Region,
+};
+
+// How SparseMap stores each chunk's tiles, indexed by `Point::chunk_index`. `Vec<T>` stores them
+// as they are, `Palette<T>` compresses them but can't hand out mutable references to tiles.
+pub trait ChunkTiles<T> {
+    // `len` default tiles
+    fn new(len: usize) -> Self;
+    fn get(&self, i: usize) -> &T;
+    fn set(&mut self, i: usize, tile: T);
+    fn replace(&mut self, i: usize, tile: T) -> T;
+    // Bytes allocated for the tiles, not counting the chunk itself
+    fn heap_size(&self) -> usize;
+    // Gives back whatever memory the tiles can do without
+    fn compact(&mut self) {}
+}
+
+impl<T: Default> ChunkTiles<T> for Vec<T> {
+    fn new(len: usize) -> Self {
+        (0..len).map(|_| T::default()).collect()
+    }
+
+    fn get(&self, i: usize) -> &T {
+        &self[i]
+    }
+
+    fn set(&mut self, i: usize, tile: T) {
+        self[i] = tile;
+    }
+
+    fn replace(&mut self, i: usize, tile: T) -> T {
+        std::mem::replace(&mut self[i], tile)
+    }
+
+    fn heap_size(&self) -> usize {
+        self.capacity() * std::mem::size_of::<T>()
+    }
+
+    fn compact(&mut self) {
+        self.shrink_to_fit();
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(serialize = "P: Serialize, C: Serialize", deserialize = "P: Point + Deserialize<'de>, C: Deserialize<'de>")))]
+pub struct SparseMap<P, T, C = Vec<T>> {
+    // Slot of each allocated chunk, keyed by the chunk's origin
     index: HashMap<P, usize>,
-    chunks: Vec<Vec<T>>,
+    chunks: Vec<Option<C>>,
+    // Slots left empty by removed chunks, reused before growing `chunks`
+    free: Vec<usize>,
 
     pub chunk_size: u32,
+    // Set by `with_default_sparsity`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    is_default: Option<fn(&T) -> bool>,
+    // Non-default tiles in each slot, where known. Mutable access to tiles forgets the counts of
+    // the chunks involved and they're recounted on the next write.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    counts: HashMap<usize, usize>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    tiles: PhantomData<T>,
 }
 
-impl<P: Point, T: Default> SparseMap<P, T> {
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub chunks: usize,
+    pub free_slots: usize,
+    pub tiles: usize,
+    // Approximate heap usage of the chunks and the index
+    pub bytes: usize,
+}
+
+impl<P: Point, T: Default, C: ChunkTiles<T>> SparseMap<P, T, C> {
     pub fn new(chunk_size: u32) -> Self {
+        P::check_chunk_size(chunk_size);
         Self {
             index: HashMap::new(),
             chunks: vec![],
+            free: vec![],
 
-            chunk_size: chunk_size,
+            chunk_size,
+            is_default: None,
+            counts: HashMap::new(),
+            tiles: PhantomData,
         }
     }
 
-    pub fn empty_in_region(&self, r: &[P; 2]) -> Vec<[P; 2]> {
-        P::chunks_in_region(r, self.chunk_size).into_iter().filter(|chunk_idx| {
-            !self.index.contains_key(&chunk_idx[0])
+    // Treats default tiles as empty: writing the default into an unallocated chunk doesn't
+    // allocate it, and a chunk is freed as soon as all its tiles are back to the default. This
+    // isn't serialized, call it again on a deserialized map.
+    pub fn with_default_sparsity(mut self) -> Self where T: PartialEq {
+        self.is_default = Some(|tile| *tile == T::default());
+        self
+    }
+
+    pub fn empty_in_region(&self, r: &Region<P>) -> Vec<Region<P>> {
+        P::chunks_in_region(r, self.chunk_size).into_iter().filter(|chunk| {
+            !self.index.contains_key(&self.key(chunk))
         }).collect()
     }
 
-    fn get(&self, p: &P) -> Option<&T> {
-        let (c, p) = p.chunk_index(self.chunk_size);
-        self.index.get(&c).and_then(|i| Some(&self.chunks[*i][p]))
+    fn key(&self, chunk: &Region<P>) -> P {
+        chunk.min.chunk_index(self.chunk_size).0
     }
 
-    fn get_mut(&mut self, p: &P) -> Option<&mut T> {
+    // None if the tile's chunk isn't allocated
+    pub fn get(&self, p: &P) -> Option<&T> {
         let (c, p) = p.chunk_index(self.chunk_size);
-        self.index.get(&c).cloned().and_then(move |i| Some(&mut self.chunks[i][p]))
+        self.index.get(&c).map(|i| self.slot(*i).get(p))
     }
 
-    fn set(&mut self, p: &P, t: T) {
-        let (c, p) = p.chunk_index(self.chunk_size);
-        if let Some(i) = self.index.get(&c) {
-            self.chunks[*i][p] = t;
+    // Allocates the tile's chunk if needed
+    pub fn set(&mut self, p: &P, t: T) {
+        let (c, j) = p.chunk_index(self.chunk_size);
+        match self.is_default {
+            Some(is_default) => {
+                if self.index.contains_key(&c) || !is_default(&t) {
+                    let i = self.allocate(c.clone());
+                    self.write(c, i, j, t);
+                }
+            },
+            None => {
+                let i = self.allocate(c);
+                self.slot_mut(i).set(j, t);
+            },
+        }
+    }
+
+    // Resets the tile to its default, returning what was there. Chunks are only freed by this
+    // with `with_default_sparsity`, otherwise see `remove_chunk` and `retain`.
+    pub fn remove(&mut self, p: &P) -> Option<T> {
+        let (c, j) = p.chunk_index(self.chunk_size);
+        let i = *self.index.get(&c)?;
+        Some(self.write(c, i, j, T::default()))
+    }
+
+    // Writes the tile and keeps count of the chunk's non-default tiles, if default tiles count as
+    // empty, freeing the chunk when it reaches zero
+    fn write(&mut self, c: P, i: usize, j: usize, t: T) -> T {
+        let is_default = match self.is_default {
+            Some(is_default) => is_default,
+            None => return self.slot_mut(i).replace(j, t),
+        };
+        let now_default = is_default(&t);
+        let old = self.slot_mut(i).replace(j, t);
+        let count = match self.counts.get(&i) {
+            Some(count) => *count + usize::from(is_default(&old)) - usize::from(now_default),
+            None => count_tiles(self.slot(i), is_default, P::max_unrolled_index(self.chunk_size)),
+        };
+        self.set_count(c, i, count);
+        old
+    }
+
+    // Frees the chunk if it has no non-default tiles left
+    fn set_count(&mut self, c: P, i: usize, count: usize) {
+        if count == 0 {
+            self.remove_chunk(&c.to_cube(self.chunk_size));
         } else {
-            let i = self.chunks.len();
-            self.chunks.push((0..P::max_unrolled_index(self.chunk_size)).map(|_| T::default()).collect());
-            self.index.insert(c, i);
-            self.chunks[i][p] = t;
+            self.counts.insert(i, count);
+        }
+    }
+
+    // Sets every tile in the region, allocating chunks as needed
+    pub fn fill(&mut self, r: &Region<P>, t: T) where T: Clone {
+        for chunk in P::chunks_in_region(r, self.chunk_size) {
+            let c = self.key(&chunk);
+            let covered: Vec<usize> = chunk.points().into_iter()
+                .filter(|p| p.contained(r))
+                .map(|p| p.chunk_index(self.chunk_size).1)
+                .collect();
+            let i = match self.is_default {
+                // Clearing tiles never needs a chunk allocated, and clearing all of one frees it
+                Some(is_default) if is_default(&t) => match self.index.get(&c) {
+                    Some(_) if covered.len() == P::max_unrolled_index(self.chunk_size) => {
+                        self.remove_chunk(&chunk);
+                        continue;
+                    },
+                    Some(i) => *i,
+                    None => continue,
+                },
+                _ => self.allocate(c.clone()),
+            };
+            let tiles = self.slot_mut(i);
+            for j in covered {
+                tiles.set(j, t.clone());
+            }
+            if let Some(is_default) = self.is_default {
+                let count = count_tiles(self.slot(i), is_default, P::max_unrolled_index(self.chunk_size));
+                self.set_count(c, i, count);
+            }
+        }
+    }
+
+    pub fn chunk(&self, chunk: &Region<P>) -> Option<&C> {
+        self.index.get(&self.key(chunk)).map(|i| self.slot(*i))
+    }
+
+    // With `with_default_sparsity` a chunk left with only default tiles stays allocated until the
+    // next write to it, or `compact`
+    pub fn chunk_mut(&mut self, chunk: &Region<P>) -> Option<&mut C> {
+        let i = *self.index.get(&self.key(chunk))?;
+        self.counts.remove(&i);
+        Some(self.slot_mut(i))
+    }
+
+    // Tiles are indexed by `Point::chunk_index`. Returns the chunk's previous tiles if it was
+    // allocated. With `with_default_sparsity` a chunk of default tiles isn't kept, inserting one
+    // frees the chunk instead.
+    pub fn insert_chunk(&mut self, chunk: &Region<P>, tiles: C) -> Option<C> {
+        let is_default = match self.is_default {
+            Some(is_default) => is_default,
+            None => return self.insert_slot(chunk, tiles),
+        };
+        let count = count_tiles(&tiles, is_default, P::max_unrolled_index(self.chunk_size));
+        if count == 0 {
+            return self.remove_chunk(chunk);
         }
+        let old = self.insert_slot(chunk, tiles);
+        self.counts.insert(self.index[&self.key(chunk)], count);
+        old
     }
 
-    pub fn region(&self, r: &[P; 2]) -> ReadGuard<'_, P, T> {
+    fn insert_slot(&mut self, chunk: &Region<P>, tiles: C) -> Option<C> {
+        let key = self.key(chunk);
+        match self.index.get(&key) {
+            Some(i) => {
+                self.counts.remove(i);
+                self.chunks[*i].replace(tiles)
+            },
+            None => {
+                let i = self.free.pop().unwrap_or_else(|| {
+                    self.chunks.push(None);
+                    self.chunks.len() - 1
+                });
+                self.chunks[i] = Some(tiles);
+                self.index.insert(key, i);
+                None
+            },
+        }
+    }
+
+    // Frees the chunk's slot for the next chunk allocated
+    pub fn remove_chunk(&mut self, chunk: &Region<P>) -> Option<C> {
+        let i = self.index.remove(&self.key(chunk))?;
+        self.counts.remove(&i);
+        self.free.push(i);
+        self.chunks[i].take()
+    }
+
+    // Removes every chunk for which `f` returns false
+    pub fn retain(&mut self, mut f: impl FnMut(&Region<P>, &C) -> bool) {
+        let removed: Vec<P> = self.index.iter()
+            .filter(|(c, i)| !f(&c.to_cube(self.chunk_size), self.slot(**i)))
+            .map(|(c, _)| c.clone())
+            .collect();
+        for c in removed {
+            self.remove_chunk(&c.to_cube(self.chunk_size));
+        }
+    }
+
+    // Every allocated chunk, in no particular order
+    pub fn chunks(&self) -> impl Iterator<Item=(Region<P>, &C)> + '_ {
+        self.index.iter().map(move |(c, i)| (c.to_cube(self.chunk_size), self.slot(*i)))
+    }
+
+    pub fn chunks_mut(&mut self) -> impl Iterator<Item=(Region<P>, &mut C)> + '_ {
+        self.counts.clear();
+        let chunk_size = self.chunk_size;
+        let mut origins: HashMap<usize, P> = self.index.iter().map(|(c, i)| (*i, c.clone())).collect();
+        self.chunks.iter_mut().enumerate().filter_map(move |(i, tiles)| {
+            Some((origins.remove(&i)?.to_cube(chunk_size), tiles.as_mut()?))
+        })
+    }
+
+    // Every tile of every allocated chunk
+    pub fn iter(&self) -> impl Iterator<Item=(P, &T)> + '_ {
+        self.chunks().flat_map(move |(chunk, tiles)| {
+            chunk.points().into_iter().map(move |p| {
+                let i = p.chunk_index(self.chunk_size).1;
+                (p, tiles.get(i))
+            })
+        })
+    }
+
+    // The number of allocated chunks
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn stats(&self) -> Stats {
+        let chunks = self.index.len();
+        let bytes = self.chunks.iter().flatten().map(|tiles| tiles.heap_size()).sum::<usize>()
+            + self.chunks.capacity() * std::mem::size_of::<Option<C>>()
+            + self.index.capacity() * std::mem::size_of::<(P, usize)>()
+            + self.free.capacity() * std::mem::size_of::<usize>();
+        Stats {
+            chunks,
+            free_slots: self.free.len(),
+            tiles: chunks * P::max_unrolled_index(self.chunk_size),
+            bytes,
+        }
+    }
+
+    // Frees chunks holding nothing but default tiles, then whatever memory is left over from
+    // freed chunks and unused palette entries. Returns how many chunks were freed.
+    pub fn compact(&mut self) -> usize where T: PartialEq {
+        let default = T::default();
+        let len = P::max_unrolled_index(self.chunk_size);
+        let before = self.len();
+        self.retain(|_, tiles| (0..len).any(|i| *tiles.get(i) != default));
+        for tiles in self.chunks.iter_mut().flatten() {
+            tiles.compact();
+        }
+        while let Some(None) = self.chunks.last() {
+            self.chunks.pop();
+        }
+        let slots = self.chunks.len();
+        self.free.retain(|i| *i < slots);
+        self.chunks.shrink_to_fit();
+        self.free.shrink_to_fit();
+        self.index.shrink_to_fit();
+        before - self.len()
+    }
+
+    fn allocate(&mut self, c: P) -> usize {
+        if let Some(i) = self.index.get(&c) {
+            return *i;
+        }
+        let chunk = c.to_cube(self.chunk_size);
+        self.insert_slot(&chunk, C::new(P::max_unrolled_index(self.chunk_size)));
+        self.index[&c]
+    }
+
+    fn slot(&self, i: usize) -> &C {
+        self.chunks[i].as_ref().unwrap()
+    }
+
+    fn slot_mut(&mut self, i: usize) -> &mut C {
+        self.chunks[i].as_mut().unwrap()
+    }
+
+    // The slots about to be handed out mutably, whose counts can't be trusted afterwards
+    fn forget_counts<V>(&mut self, slots: &HashMap<usize, V>) -> Vec<usize> {
+        slots.keys().map(|i| {
+            self.counts.remove(i);
+            *i
+        }).collect()
+    }
+
+    // For every allocated chunk touching `sub`, the points in both `region` and `sub` in the
+    // order the chunk stores its tiles
+    fn chunk_points(&self, region: &Region<P>, sub: &Region<P>) -> HashMap<usize, Vec<Option<P>>> {
+        P::chunks_in_region(sub, self.chunk_size).into_iter().filter_map(|chunk| {
+            let i = *self.index.get(&self.key(&chunk))?;
+            let mut points = vec![None; P::max_unrolled_index(self.chunk_size)];
+            for p in chunk.points() {
+                if p.contained(region) && p.contained(sub) {
+                    let j = p.chunk_index(self.chunk_size).1;
+                    points[j] = Some(p);
+                }
+            }
+            Some((i, points))
+        }).collect()
+    }
+
+    fn iter_region(&self, region: &Region<P>, sub: &Region<P>) -> impl Iterator<Item=(P, &T)> + '_ {
+        let mut chunks: Vec<_> = self.chunk_points(region, sub).into_iter().collect();
+        chunks.sort_by_key(|(i, _)| *i);
+        chunks.into_iter().flat_map(move |(i, points)| {
+            let tiles = self.slot(i);
+            points.into_iter().enumerate().filter_map(move |(j, p)| Some((p?, tiles.get(j))))
+        })
+    }
+
+    pub fn region(&self, r: &Region<P>) -> ReadGuard<'_, P, T, C> {
         ReadGuard {
             owner: self,
             region: r.clone(),
         }
     }
 
-    pub fn region_mut(&mut self, r: &[P; 2]) -> WriteGuard<'_, P, T> {
+    pub fn region_mut(&mut self, r: &Region<P>) -> WriteGuard<'_, P, T, C> {
         WriteGuard {
             owner: self,
             region: r.clone(),
@@ -62,56 +403,167 @@ impl<P: Point, T: Default> SparseMap<P, T> {
     }
 }
 
-pub struct ReadGuard<'a, P, T> {
-    owner: &'a SparseMap<P, T>,
-    region: [P; 2],
+// Mutable references to tiles are only available with uncompressed chunks
+impl<P: Point, T: Default> SparseMap<P, T> {
+    pub fn get_mut(&mut self, p: &P) -> Option<&mut T> {
+        let (c, p) = p.chunk_index(self.chunk_size);
+        let i = *self.index.get(&c)?;
+        self.counts.remove(&i);
+        Some(&mut self.slot_mut(i)[p])
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item=(P, &mut T)> + '_ {
+        let chunk_size = self.chunk_size;
+        self.chunks_mut().flat_map(move |(chunk, tiles)| {
+            let mut points: Vec<Option<P>> = vec![None; tiles.len()];
+            for p in chunk.points() {
+                let i = p.chunk_index(chunk_size).1;
+                points[i] = Some(p);
+            }
+            tiles.iter_mut().zip(points).filter_map(|(tile, p)| Some((p?, tile)))
+        })
+    }
+
+    fn iter_region_mut(&mut self, region: &Region<P>, sub: &Region<P>) -> impl Iterator<Item=(P, &mut T)> + '_ {
+        let mut points = self.chunk_points(region, sub);
+        let slots = self.forget_counts(&points);
+        slots_mut(&mut self.chunks, slots).into_iter().flat_map(move |(i, tiles)| {
+            let points = points.remove(&i).unwrap_or_default();
+            tiles.iter_mut().zip(points).filter_map(|(tile, p)| Some((p?, tile)))
+        })
+    }
+}
+
+// Non-default tiles in a chunk of `len` tiles
+fn count_tiles<T, C: ChunkTiles<T>>(tiles: &C, is_default: fn(&T) -> bool, len: usize) -> usize {
+    (0..len).filter(|j| !is_default(tiles.get(*j))).count()
 }
 
-pub struct WriteGuard<'a, P, T> {
-    owner: &'a mut SparseMap<P, T>,
-    region: [P; 2],
+// The given allocated slots, in slot order, without going through the others
+fn slots_mut<C>(chunks: &mut [Option<C>], mut slots: Vec<usize>) -> Vec<(usize, &mut C)> {
+    slots.sort_unstable();
+    let mut rest = chunks;
+    let mut start = 0;
+    slots.into_iter().map(|i| {
+        let (slot, tail) = std::mem::take(&mut rest)[i - start..].split_first_mut().unwrap();
+        rest = tail;
+        start = i + 1;
+        (i, slot.as_mut().unwrap())
+    }).collect()
 }
 
-impl<'a, P: Point, T: Default> ReadGuard<'a, P, T> {
-    pub fn get(&self, p: &P) -> Result<Option<&T>, ()> {
+pub struct ReadGuard<'a, P, T, C = Vec<T>> {
+    owner: &'a SparseMap<P, T, C>,
+    region: Region<P>,
+}
+
+pub struct WriteGuard<'a, P, T, C = Vec<T>> {
+    owner: &'a mut SparseMap<P, T, C>,
+    region: Region<P>,
+}
+
+impl<'a, P: Point, T: Default, C: ChunkTiles<T>> ReadGuard<'a, P, T, C> {
+    pub fn get(&self, p: &P) -> error::Result<Option<&T>> {
         if p.contained(&self.region) {
             Ok(self.owner.get(p))
         } else {
-            Err(())
+            Err(Error::OutOfRegion)
         }
     }
+
+    // Every tile in the region which belongs to an allocated chunk
+    pub fn iter(&self) -> impl Iterator<Item=(P, &T)> + '_ {
+        self.owner.iter_region(&self.region, &self.region)
+    }
+
+    pub fn iter_region(&self, sub: &Region<P>) -> impl Iterator<Item=(P, &T)> + '_ {
+        self.owner.iter_region(&self.region, sub)
+    }
 }
 
-impl<'a, P: Point, T: Default> WriteGuard<'a, P, T> {
-    pub fn get(&self, p: &P) -> Result<Option<&T>, ()> {
+impl<'a, P: Point, T: Default, C: ChunkTiles<T>> WriteGuard<'a, P, T, C> {
+    pub fn get(&self, p: &P) -> error::Result<Option<&T>> {
         if p.contained(&self.region) {
             Ok(self.owner.get(p))
         } else {
-            Err(())
+            Err(Error::OutOfRegion)
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(P, &T)> + '_ {
+        self.owner.iter_region(&self.region, &self.region)
+    }
+
+    pub fn iter_region(&self, sub: &Region<P>) -> impl Iterator<Item=(P, &T)> + '_ {
+        self.owner.iter_region(&self.region, sub)
+    }
+
+    pub fn set(&mut self, p: &P, t: T) -> error::Result<()> {
+        if p.contained(&self.region) {
+            self.owner.set(p, t);
+            Ok(())
+        } else {
+            Err(Error::OutOfRegion)
         }
     }
 
-    pub fn get_mut(&mut self, p: &P) -> Result<Option<&mut T>, ()> {
+    pub fn remove(&mut self, p: &P) -> error::Result<Option<T>> {
         if p.contained(&self.region) {
-            Ok(self.owner.get_mut(p))
+            Ok(self.owner.remove(p))
         } else {
-            Err(())
+            Err(Error::OutOfRegion)
         }
     }
 
-    pub fn set(&mut self, p: &P, t: T) -> Result<(), ()> {
+    // Fills the part of `sub` inside the guarded region
+    pub fn fill(&mut self, sub: &Region<P>, t: T) where T: Clone {
+        if let Some(sub) = self.region.intersection(sub) {
+            self.owner.fill(&sub, t);
+        }
+    }
+}
+
+impl<'a, P: Point, T: Default> WriteGuard<'a, P, T> {
+    pub fn get_mut(&mut self, p: &P) -> error::Result<Option<&mut T>> {
         if p.contained(&self.region) {
-            self.owner.set(p, t);
-            Ok(())
+            Ok(self.owner.get_mut(p))
         } else {
-            Err(())
+            Err(Error::OutOfRegion)
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item=(P, &mut T)> + '_ {
+        self.owner.iter_region_mut(&self.region, &self.region)
+    }
+
+    pub fn iter_region_mut(&mut self, sub: &Region<P>) -> impl Iterator<Item=(P, &mut T)> + '_ {
+        self.owner.iter_region_mut(&self.region, sub)
+    }
+
+    pub fn for_each_mut(&mut self, sub: &Region<P>, mut f: impl FnMut(&P, &mut T)) {
+        for (p, tile) in self.iter_region_mut(sub) {
+            f(&p, tile);
         }
     }
+
+    #[cfg(feature = "rayon")]
+    pub fn par_for_each_mut(&mut self, sub: &Region<P>, f: impl Fn(&P, &mut T) + Sync + Send) where T: Send {
+        let points = self.owner.chunk_points(&self.region, sub);
+        let slots = self.owner.forget_counts(&points);
+        slots_mut(&mut self.owner.chunks, slots).into_par_iter().for_each(|(i, tiles)| {
+            for (tile, p) in tiles.iter_mut().zip(&points[&i]) {
+                if let Some(p) = p {
+                    f(p, tile);
+                }
+            }
+        });
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::palette::Palette;
 
     #[derive(Default, Debug)]
     struct Tile {
@@ -122,7 +574,7 @@ mod tests {
     #[test]
     fn write_points() {
         let mut map:SparseMap<[i32; 2], Tile> = SparseMap::new(10);
-        let mut region = map.region_mut(&[[0, 0], [100, 100]]);
+        let mut region = map.region_mut(&Region::new([0, 0], [100, 100]));
         region.set(&[50, 50], Tile { a: 42, b: 43 }).unwrap();
         eprintln!("{:?}", region.get(&[50, 50]).unwrap().unwrap());
         assert!(region.get(&[50, 50]).unwrap().unwrap().a == 42);
@@ -131,11 +583,155 @@ mod tests {
     #[test]
     fn write_points_then_read() {
         let mut map:SparseMap<[i32; 2], Tile> = SparseMap::new(10);
-        let mut region = map.region_mut(&[[0, 0], [100, 100]]);
+        let mut region = map.region_mut(&Region::new([0, 0], [100, 100]));
         region.set(&[50, 50], Tile { a: 42, b: 43 }).unwrap();
 
-        let region = map.region(&[[0, 0], [100, 100]]);
+        let region = map.region(&Region::new([0, 0], [100, 100]));
         let t = region.get(&[50, 50]).unwrap().unwrap();
         assert!(t.a == 42);
     }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize_round_trip() {
+        let mut map:SparseMap<[i32; 2], i32> = SparseMap::new(10);
+        map.region_mut(&Region::new([0, 0], [100, 100])).set(&[50, 50], 42).unwrap();
+
+        let data = bincode::serialize(&map).unwrap();
+        let map:SparseMap<[i32; 2], i32> = bincode::deserialize(&data).unwrap();
+        let region = map.region(&Region::new([0, 0], [100, 100]));
+        assert_eq!(region.get(&[50, 50]).unwrap(), Some(&42));
+        assert_eq!(region.get(&[0, 0]).unwrap(), None);
+    }
+
+    #[test]
+    fn palette_chunks() {
+        let mut map: SparseMap<[i32; 2], u8, Palette<u8>> = SparseMap::new(16);
+        let mut region = map.region_mut(&Region::new([0, 0], [32, 32]));
+        for p in Region::new([0, 0], [32, 8]).points() {
+            region.set(&p, (p[0] % 3) as u8).unwrap();
+        }
+        region.set(&[20, 20], 7).unwrap();
+
+        let region = map.region(&Region::new([0, 0], [32, 32]));
+        assert_eq!(region.get(&[5, 5]).unwrap(), Some(&2));
+        assert_eq!(region.get(&[20, 20]).unwrap(), Some(&7));
+        assert_eq!(region.get(&[5, 20]).unwrap(), None);
+        assert_eq!(region.iter().filter(|(_, tile)| **tile == 1).count(), 11 * 8);
+        assert_eq!(map.chunk(&Region::new([0, 0], [16, 16])).unwrap().bits_per_tile(), 2);
+        assert_eq!(map.chunk(&Region::new([16, 16], [32, 32])).unwrap().palette(), &[0, 7]);
+    }
+
+    #[test]
+    fn chunks_and_slots() {
+        let mut map: SparseMap<[i32; 2], i32> = SparseMap::new(4);
+        map.fill(&Region::new([2, 2], [10, 6]), 1);
+        assert_eq!(map.len(), 6);
+        assert_eq!(map.iter().filter(|(_, tile)| **tile == 1).count(), 32);
+        assert_eq!(map.get(&[1, 1]), Some(&0));
+        assert_eq!(map.get(&[20, 20]), None);
+
+        let a = Region::new([0, 0], [4, 4]);
+        assert_eq!(map.remove(&[3, 3]), Some(1));
+        assert_eq!(map.remove(&[20, 20]), None);
+        map.chunk_mut(&a).unwrap()[..4].copy_from_slice(&[5, 6, 7, 8]);
+        assert_eq!(map.get(&[3, 0]), Some(&8));
+        *map.get_mut(&[0, 0]).unwrap() += 1;
+
+        let removed = map.remove_chunk(&a).unwrap();
+        assert_eq!(removed[0], 6);
+        assert_eq!(map.stats().free_slots, 1);
+        map.set(&[-1, -1], 3);
+        assert_eq!(map.stats().free_slots, 0, "the slot is reused");
+        assert_eq!(map.chunks.len(), 6);
+
+        map.retain(|chunk, tiles| chunk.min[1] < 0 || tiles.iter().all(|tile| *tile == 0));
+        assert_eq!(map.chunks().map(|(chunk, _)| chunk).collect::<Vec<_>>(), vec![Region::new([-4, -4], [0, 0])]);
+        for (_, tile) in map.iter_mut() {
+            *tile += 1;
+        }
+        let stats = map.stats();
+        assert_eq!((stats.chunks, stats.free_slots, stats.tiles), (1, 5, 16));
+        assert!(stats.bytes >= 16 * std::mem::size_of::<i32>());
+        assert_eq!(map.iter().map(|(_, tile)| *tile).sum::<i32>(), 19);
+    }
+
+    #[test]
+    fn default_sparsity() {
+        let mut fog: SparseMap<[i32; 2], bool> = SparseMap::new(4).with_default_sparsity();
+        fog.set(&[1, 1], false);
+        fog.fill(&Region::new([0, 0], [100, 100]), false);
+        assert!(fog.is_empty());
+
+        fog.fill(&Region::new([2, 2], [6, 4]), true);
+        assert_eq!(fog.len(), 2);
+        fog.set(&[5, 2], false);
+        assert_eq!(fog.remove(&[5, 3]), Some(true));
+        assert_eq!(fog.len(), 2);
+        fog.set(&[4, 2], false);
+        fog.set(&[4, 3], false);
+        assert_eq!(fog.len(), 1, "the chunk is freed once it's all default again");
+
+        // Counts are rebuilt after tiles have been modified in place
+        *fog.get_mut(&[3, 3]).unwrap() = false;
+        fog.fill(&Region::new([0, 0], [4, 4]), false);
+        assert!(fog.is_empty());
+        assert_eq!(fog.stats().free_slots, 2);
+        fog.compact();
+        assert_eq!(fog.stats(), Stats { bytes: fog.stats().bytes, ..Stats::default() });
+
+        fog.fill(&Region::new([0, 0], [8, 4]), true);
+        fog.fill(&Region::new([0, 0], [6, 4]), false);
+        assert_eq!(fog.len(), 1, "a fully cleared chunk is freed, a partly cleared one kept");
+        assert_eq!(fog.get(&[6, 0]), Some(&true));
+        fog.fill(&Region::new([6, 0], [8, 4]), false);
+        assert!(fog.is_empty());
+
+        assert_eq!(fog.insert_chunk(&Region::new([0, 0], [4, 4]), vec![false; 16]), None);
+        assert!(fog.is_empty(), "default chunks aren't inserted");
+        fog.insert_chunk(&Region::new([0, 0], [4, 4]), vec![true; 16]);
+        assert_eq!(fog.insert_chunk(&Region::new([0, 0], [4, 4]), vec![false; 16]), Some(vec![true; 16]));
+        assert!(fog.is_empty(), "and replacing a chunk with default tiles frees it");
+
+        let mut damage: SparseMap<[i32; 2], u8, Palette<u8>> = SparseMap::new(4);
+        damage.fill(&Region::new([0, 0], [8, 4]), 3);
+        damage.set(&[1, 1], 9);
+        damage.fill(&Region::new([4, 0], [8, 4]), 0);
+        damage.fill(&Region::new([0, 0], [4, 4]), 3);
+        assert_eq!(damage.compact(), 1);
+        assert_eq!(damage.len(), 1);
+        let chunk = damage.chunk(&Region::new([0, 0], [4, 4])).unwrap();
+        assert_eq!((chunk.palette(), chunk.bits_per_tile()), (&[3][..], 0));
+    }
+
+    #[test]
+    fn iterate_region() {
+        let mut map: SparseMap<[i32; 2], i32> = SparseMap::new(4);
+        let r = Region::new([0, 0], [8, 8]);
+        let mut region = map.region_mut(&r);
+        region.set(&[1, 1], 1).unwrap();
+        region.set(&[6, 1], 2).unwrap();
+        assert_eq!(region.iter().count(), 32);
+
+        region.for_each_mut(&Region::new([2, 0], [6, 4]), |p, tile| *tile += p[0]);
+        let region = map.region(&Region::new([0, 0], [5, 2]));
+        let tiles: HashMap<[i32; 2], i32> = region.iter().map(|(p, tile)| (p, *tile)).collect();
+        assert_eq!(tiles.len(), 10);
+        assert_eq!(tiles[&[1, 1]], 1);
+        assert_eq!(tiles[&[4, 1]], 4);
+        assert_eq!(region.iter_region(&Region::new([4, 0], [10, 10])).count(), 2);
+    }
+
+    #[test]
+    fn region_writes_keep_other_counts() {
+        let mut fog: SparseMap<[i32; 2], bool> = SparseMap::new(4).with_default_sparsity();
+        fog.fill(&Region::new([0, 0], [12, 4]), true);
+        let slots: Vec<usize> = [[0, 0], [8, 0]].iter().map(|c| fog.index[c]).collect();
+        assert!(slots.iter().all(|i| fog.counts.contains_key(i)));
+
+        fog.region_mut(&Region::new([4, 0], [8, 4])).for_each_mut(&Region::new([4, 0], [8, 4]), |_, tile| *tile = false);
+        assert!(slots.iter().all(|i| fog.counts.contains_key(i)));
+        assert!(!fog.counts.contains_key(&fog.index[&[4, 0]]));
+        assert_eq!(fog.iter().filter(|(_, tile)| **tile).count(), 32);
+    }
 }
@@ -0,0 +1,285 @@
+use std::cell::UnsafeCell;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crate::{
+    point::Point,
+    region::Region,
+};
+
+/// Where a Map keeps its tiles. Map only touches tiles through a `View` of a region it holds a
+/// region lock on, and it's the region lock which keeps readers and writers apart, so a storage
+/// only has to synchronize adding and removing chunks, not access to the tiles themselves.
+///
+/// # Safety
+///
+/// A tile pointer handed out by a view must stay valid until the view is dropped or the chunk
+/// holding it is removed or replaced, and different points must get different tiles.
+pub unsafe trait Storage<P, T>: Send + Sync {
+    type View: View<P, T>;
+
+    fn new(chunk_size: u32) -> Self;
+
+    // Tiles are given in `Point::points_in_region` order. Replaces the chunk if it's loaded.
+    fn insert(&self, chunk: &Region<P>, tiles: Vec<T>);
+
+    // Fills the chunk with default tiles unless it's already loaded
+    fn insert_default(&self, chunk: &Region<P>);
+
+    fn remove(&self, chunk: &Region<P>) -> bool;
+
+    fn view(&self, region: &Region<P>) -> Self::View;
+}
+
+// The loaded tiles of a region. Dereferencing its pointers is only sound while holding a region
+// lock covering the tile, a write lock to create a `&mut`.
+pub trait View<P, T>: Send {
+    fn tile(&self, p: &P) -> Option<*mut T>;
+
+    // Every loaded tile in both `sub` and the view's region, each once
+    fn tiles(&self, sub: Region<P>) -> impl Iterator<Item=(P, *mut T)> + '_;
+}
+
+// A chunk's tiles, indexed by `Point::chunk_index`
+pub struct Chunk<T> {
+    tiles: Box<[UnsafeCell<T>]>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Chunk<T> {}
+
+// Tiles stored a chunk at a time in dense arrays, keyed by the chunk's origin. The table lock is
+// only taken to add or remove chunks and when a view is taken. This is what Map uses by default.
+pub struct ChunkedStorage<P, T> {
+    chunk_size: u32,
+    chunks: RwLock<HashMap<P, Arc<Chunk<T>>>>,
+}
+
+impl<P: Point, T> ChunkedStorage<P, T> {
+    fn key(&self, chunk: &Region<P>) -> P {
+        chunk.min.chunk_index(self.chunk_size).0
+    }
+}
+
+unsafe impl<P: Point, T: Default + Send + Sync> Storage<P, T> for ChunkedStorage<P, T> {
+    type View = ChunkedView<P, T>;
+
+    fn new(chunk_size: u32) -> Self {
+        P::check_chunk_size(chunk_size);
+        Self {
+            chunk_size,
+            chunks: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn insert(&self, chunk: &Region<P>, tiles: Vec<T>) {
+        let mut slots: Vec<Option<T>> = (0..P::max_unrolled_index(self.chunk_size)).map(|_| None).collect();
+        for (p, tile) in chunk.points().into_iter().zip(tiles) {
+            slots[p.chunk_index(self.chunk_size).1] = Some(tile);
+        }
+        let tiles = slots.into_iter().map(|tile| UnsafeCell::new(tile.unwrap_or_default())).collect();
+        self.chunks.write().unwrap().insert(self.key(chunk), Arc::new(Chunk { tiles }));
+    }
+
+    fn insert_default(&self, chunk: &Region<P>) {
+        let size = P::max_unrolled_index(self.chunk_size);
+        self.chunks.write().unwrap().entry(self.key(chunk)).or_insert_with(|| {
+            let tiles = (0..size).map(|_| UnsafeCell::new(T::default())).collect();
+            Arc::new(Chunk { tiles })
+        });
+    }
+
+    fn remove(&self, chunk: &Region<P>) -> bool {
+        self.chunks.write().unwrap().remove(&self.key(chunk)).is_some()
+    }
+
+    // Holds on to the chunks overlapping the region so lookups don't go back to the table
+    fn view(&self, region: &Region<P>) -> ChunkedView<P, T> {
+        let chunks = self.chunks.read().unwrap();
+        let chunks = P::chunks_in_region(region, self.chunk_size).into_iter().filter_map(|chunk| {
+            let key = self.key(&chunk);
+            let loaded = chunks.get(&key)?.clone();
+            Some((key, (chunk, loaded)))
+        }).collect();
+        ChunkedView {
+            chunks,
+            chunk_size: self.chunk_size,
+            region: region.clone(),
+        }
+    }
+}
+
+pub struct ChunkedView<P, T> {
+    chunks: HashMap<P, (Region<P>, Arc<Chunk<T>>)>,
+    chunk_size: u32,
+    region: Region<P>,
+}
+
+impl<P: Point, T: Send + Sync> View<P, T> for ChunkedView<P, T> {
+    fn tile(&self, p: &P) -> Option<*mut T> {
+        if !p.contained(&self.region) {
+            return None;
+        }
+        let (c, i) = p.chunk_index(self.chunk_size);
+        self.chunks.get(&c).map(|(_, chunk)| chunk.tiles[i].get())
+    }
+
+    fn tiles(&self, sub: Region<P>) -> impl Iterator<Item=(P, *mut T)> + '_ {
+        let chunks = P::chunks_in_region(&sub, self.chunk_size);
+        chunks.into_iter().filter_map(move |chunk| self.chunks.get(&chunk.min.chunk_index(self.chunk_size).0)).flat_map(move |(chunk, loaded)| {
+            let sub = sub.clone();
+            chunk.points().into_iter()
+                .filter(move |p| p.contained(&self.region) && p.contained(&sub))
+                .map(move |p| {
+                    let i = p.chunk_index(self.chunk_size).1;
+                    (p, loaded.tiles[i].get())
+                })
+        })
+    }
+}
+
+// Every tile boxed on its own under its point, as Map stored tiles before `ChunkedStorage`. Views
+// look tiles up in the shared table rather than holding on to chunks, so taking one is cheap
+// however large the region, but each lookup takes the table lock.
+pub struct HashStorage<P, T> {
+    tiles: Arc<RwLock<HashMap<P, Box<Tile<T>>>>>,
+}
+
+struct Tile<T>(UnsafeCell<T>);
+
+unsafe impl<T: Send + Sync> Sync for Tile<T> {}
+
+// Boxing keeps each tile where it is while the table grows
+unsafe impl<P: Point, T: Default + Send + Sync> Storage<P, T> for HashStorage<P, T> {
+    type View = HashView<P, T>;
+
+    fn new(_chunk_size: u32) -> Self {
+        Self {
+            tiles: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    fn insert(&self, chunk: &Region<P>, tiles: Vec<T>) {
+        let mut table = self.tiles.write().unwrap();
+        for (p, tile) in chunk.points().into_iter().zip(tiles) {
+            table.insert(p, Box::new(Tile(UnsafeCell::new(tile))));
+        }
+    }
+
+    fn insert_default(&self, chunk: &Region<P>) {
+        let mut table = self.tiles.write().unwrap();
+        for p in chunk.points() {
+            table.entry(p).or_insert_with(|| Box::new(Tile(UnsafeCell::new(T::default()))));
+        }
+    }
+
+    fn remove(&self, chunk: &Region<P>) -> bool {
+        let mut table = self.tiles.write().unwrap();
+        let mut removed = false;
+        for p in chunk.points() {
+            removed |= table.remove(&p).is_some();
+        }
+        removed
+    }
+
+    fn view(&self, region: &Region<P>) -> HashView<P, T> {
+        HashView {
+            tiles: self.tiles.clone(),
+            region: region.clone(),
+        }
+    }
+}
+
+pub struct HashView<P, T> {
+    tiles: Arc<RwLock<HashMap<P, Box<Tile<T>>>>>,
+    region: Region<P>,
+}
+
+impl<P: Point, T: Send + Sync> View<P, T> for HashView<P, T> {
+    fn tile(&self, p: &P) -> Option<*mut T> {
+        if !p.contained(&self.region) {
+            return None;
+        }
+        self.tiles.read().unwrap().get(p).map(|tile| tile.0.get())
+    }
+
+    fn tiles(&self, sub: Region<P>) -> impl Iterator<Item=(P, *mut T)> + '_ {
+        sub.iter().filter_map(move |p| {
+            let tile = self.tile(&p)?;
+            Some((p, tile))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::wrapping::Wrapping;
+
+    // What Map relies on from every storage
+    fn conformance<S: Storage<[i32; 2], u32>>() {
+        let storage = S::new(4);
+        let a = Region::new([0, 0], [4, 4]);
+        let b = Region::new([4, 0], [8, 4]);
+        let both = Region::new([2, 1], [6, 3]);
+
+        assert!(storage.view(&both).tile(&[2, 1]).is_none());
+        storage.insert_default(&a);
+        let view = storage.view(&both);
+        assert_eq!(unsafe { *view.tile(&[2, 1]).unwrap() }, 0);
+        assert!(view.tile(&[4, 1]).is_none());
+        assert!(view.tile(&[0, 0]).is_none(), "outside the view's region");
+        assert_eq!(view.tiles(both).count(), 4);
+
+        // Tiles come back in the order given
+        storage.insert(&b, (0..16).collect());
+        let view = storage.view(&b);
+        for (i, p) in b.points().iter().enumerate() {
+            assert_eq!(unsafe { *view.tile(p).unwrap() }, i as u32);
+        }
+
+        // Writes land in the same tile whichever view they're made through
+        unsafe { *storage.view(&both).tile(&[3, 2]).unwrap() = 7 };
+        assert_eq!(unsafe { *storage.view(&a).tile(&[3, 2]).unwrap() }, 7);
+        storage.insert_default(&a);
+        assert_eq!(unsafe { *storage.view(&a).tile(&[3, 2]).unwrap() }, 7, "loaded chunks are kept");
+
+        let view = storage.view(&both);
+        let tiles: Vec<([i32; 2], *mut u32)> = view.tiles(Region::new([0, 0], [5, 2])).collect();
+        let points: HashSet<[i32; 2]> = tiles.iter().map(|(p, _)| *p).collect();
+        let distinct: HashSet<*mut u32> = tiles.iter().map(|(_, tile)| *tile).collect();
+        assert_eq!(points, Region::new([2, 1], [5, 2]).points().into_iter().collect());
+        assert_eq!(distinct.len(), tiles.len());
+
+        assert!(storage.remove(&a));
+        assert!(!storage.remove(&a));
+        let view = storage.view(&both);
+        assert!(view.tile(&[3, 2]).is_none());
+        assert_eq!(view.tiles(both).count(), 4);
+    }
+
+    fn wrapping_conformance<S: Storage<Wrapping<8, 0>, u32>>() {
+        let storage = S::new(4);
+        let chunk = Region::new(Wrapping::new(4, 0), Wrapping::new(8, 4));
+        storage.insert(&chunk, (0..16).collect());
+        // Straddles the seam
+        let view = storage.view(&Region::new(Wrapping::new(6, 0), Wrapping::new(10, 1)));
+        assert_eq!(view.tiles(Region::new(Wrapping::new(0, 0), Wrapping::new(8, 1))).count(), 2);
+        let p = Wrapping::new(7, 0);
+        let i = chunk.points().iter().position(|other| *other == p).unwrap();
+        assert_eq!(unsafe { *view.tile(&p).unwrap() }, i as u32);
+    }
+
+    #[test]
+    fn chunked_storage() {
+        conformance::<ChunkedStorage<_, _>>();
+        wrapping_conformance::<ChunkedStorage<_, _>>();
+    }
+
+    #[test]
+    fn hash_storage() {
+        conformance::<HashStorage<_, _>>();
+        wrapping_conformance::<HashStorage<_, _>>();
+    }
+}
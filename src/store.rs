@@ -0,0 +1,160 @@
+use std::io;
+#[cfg(feature = "serde")]
+use std::{
+    fs,
+    path::PathBuf,
+};
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{
+    point::Point,
+    region::Region,
+};
+
+// Tiles are passed in the order given by `Point::points_in_region` for the chunk
+pub trait ChunkStore<P, T>: Send + Sync where P: Point {
+    fn load(&self, chunk: &Region<P>) -> io::Result<Option<Vec<T>>>;
+    fn save(&self, chunk: &Region<P>, tiles: &[&T]) -> io::Result<()>;
+}
+
+// Stores each chunk as a bincode encoded file in a single directory
+#[cfg(feature = "serde")]
+pub struct FileStore {
+    root: PathBuf,
+}
+
+#[cfg(feature = "serde")]
+impl FileStore {
+    pub fn new(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(Self {
+            root,
+        })
+    }
+
+    fn path<P: Serialize>(&self, chunk: &Region<P>) -> io::Result<PathBuf> {
+        let key = bincode::serialize(chunk).map_err(to_io_error)?;
+        let name: String = key.iter().map(|b| format!("{:02x}", b)).collect();
+        Ok(self.root.join(format!("chunk_{}.bin", name)))
+    }
+}
+
+#[cfg(feature = "serde")]
+fn to_io_error(e: bincode::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(feature = "serde")]
+impl<P: Point + Serialize, T: Serialize + DeserializeOwned> ChunkStore<P, T> for FileStore {
+    fn load(&self, chunk: &Region<P>) -> io::Result<Option<Vec<T>>> {
+        match fs::read(self.path(chunk)?) {
+            Ok(data) => Ok(Some(bincode::deserialize(&data).map_err(to_io_error)?)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save(&self, chunk: &Region<P>, tiles: &[&T]) -> io::Result<()> {
+        let path = self.path(chunk)?;
+        let data = bincode::serialize(tiles).map_err(to_io_error)?;
+        // Write then rename so that a crash can't leave a half written chunk behind
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(tmp, path)
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+    use crate::{
+        Map, WriteGuard,
+        generator::Generator,
+    };
+
+    #[derive(Clone)]
+    struct Fill;
+
+    impl Generator<[i32; 2], u32> for Fill {
+        fn generate(&mut self, chunk: &mut WriteGuard<'_, [i32; 2], u32>, core_region: &Region<[i32; 2]>, _umbra: &Region<[i32; 2]>) {
+            for p in <[i32; 2] as Point>::points_in_region(core_region) {
+                *chunk.get_mut(&p).unwrap() = 1;
+            }
+        }
+    }
+
+    // Adds one to every loaded tile it can reach, including in generated neighbours
+    #[derive(Clone)]
+    struct Smear;
+
+    impl Generator<[i32; 2], u32> for Smear {
+        fn generate(&mut self, chunk: &mut WriteGuard<'_, [i32; 2], u32>, _core_region: &Region<[i32; 2]>, umbra: &Region<[i32; 2]>) {
+            chunk.for_each_mut(umbra, |_, tile| *tile += 1);
+        }
+    }
+
+    fn new_map(root: &PathBuf) -> Map<[i32; 2], u32> {
+        Map::new(vec![Box::new(Fill)], 8, 0).with_store(Box::new(FileStore::new(root).unwrap()))
+    }
+
+    #[test]
+    fn modified_chunks_survive_eviction_and_flush() {
+        let root = std::env::temp_dir().join(format!("grid_builder_store_{}", std::process::id()));
+
+        let map = new_map(&root);
+        map.maybe_generate(Region::new([0, 0], [16, 8]));
+        *map.get_mut(&[1, 1]) = 42;
+        map.unload(&Region::new([0, 0], [16, 8]));
+        map.maybe_generate(Region::new([0, 0], [16, 8]));
+        assert_eq!(*map.get(&[1, 1]), 42);
+        assert_eq!(*map.get(&[2, 1]), 1);
+
+        *map.get_mut(&[9, 1]) = 43;
+        map.flush().unwrap();
+
+        let reloaded = new_map(&root);
+        reloaded.maybe_generate(Region::new([0, 0], [24, 8]));
+        assert_eq!(*reloaded.get(&[1, 1]), 42);
+        assert_eq!(*reloaded.get(&[9, 1]), 43);
+        assert_eq!(*reloaded.get(&[17, 1]), 1);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn umbra_writes_are_saved() {
+        let root = std::env::temp_dir().join(format!("grid_builder_umbra_{}", std::process::id()));
+
+        let map: Map<[i32; 2], u32> = Map::new(vec![Box::new(Smear)], 8, 0).with_store(Box::new(FileStore::new(&root).unwrap()));
+        map.maybe_generate(Region::new([0, 0], [8, 8]));
+        map.maybe_generate(Region::new([8, 0], [16, 8]));
+        assert_eq!(*map.get(&[7, 1]), 2);
+        map.unload(&Region::new([0, 0], [16, 8]));
+        map.maybe_generate(Region::new([0, 0], [8, 8]));
+        assert_eq!(*map.get(&[7, 1]), 2);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn untouched_chunks_are_not_saved() {
+        let root = std::env::temp_dir().join(format!("grid_builder_untouched_{}", std::process::id()));
+
+        // The second chunk's umbra reaches into the first one, but nothing is written there
+        let map = new_map(&root);
+        map.maybe_generate(Region::new([0, 0], [8, 8]));
+        map.maybe_generate(Region::new([8, 0], [16, 8]));
+        drop(map.region_mut(&Region::new([0, 0], [16, 8])));
+        map.flush().unwrap();
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 0);
+
+        *map.get_mut(&[9, 1]) = 5;
+        map.unload(&Region::new([0, 0], [16, 8]));
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 1);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
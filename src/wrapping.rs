@@ -0,0 +1,409 @@
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+use crate::{
+    point::Point,
+    region::Region,
+};
+
+// A point on a world which wraps around after WIDTH tiles on the x axis and HEIGHT tiles on the y
+// axis, a size of 0 meaning that axis doesn't wrap. So `Wrapping<1024, 0>` is a cylinder and
+// `Wrapping<1024, 512>` a torus.
+//
+// Coordinates are not normalized, a region from x=-8 to x=8 is a region which straddles the seam,
+// but equality and hashing are done on the wrapped coordinates so x=-1 and x=WIDTH-1 are the
+// same tile. Both sizes must be multiples of the chunk size for chunks to line up at the seam.
+#[derive(Copy, Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Wrapping<const WIDTH: u32, const HEIGHT: u32> {
+    pub x: i32,
+    pub y: i32,
+}
+
+impl<const WIDTH: u32, const HEIGHT: u32> Wrapping<WIDTH, HEIGHT> {
+    pub fn new(x: i32, y: i32) -> Self {
+        Self {
+            x,
+            y,
+        }
+    }
+
+    // The same point with coordinates inside [0, WIDTH) and [0, HEIGHT)
+    pub fn normalized(&self) -> Self {
+        Self::new(wrap(self.x, WIDTH), wrap(self.y, HEIGHT))
+    }
+
+    fn to_array(self) -> [i32; 2] {
+        [self.x, self.y]
+    }
+
+    fn from_array(p: [i32; 2]) -> Self {
+        Self::new(p[0], p[1])
+    }
+}
+
+impl<const WIDTH: u32, const HEIGHT: u32> PartialEq for Wrapping<WIDTH, HEIGHT> {
+    fn eq(&self, other: &Self) -> bool {
+        self.normalized().to_array() == other.normalized().to_array()
+    }
+}
+
+impl<const WIDTH: u32, const HEIGHT: u32> Eq for Wrapping<WIDTH, HEIGHT> {}
+
+impl<const WIDTH: u32, const HEIGHT: u32> Hash for Wrapping<WIDTH, HEIGHT> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let p = self.normalized();
+        state.write_i32(p.x);
+        state.write_i32(p.y);
+    }
+}
+
+fn wrap(v: i32, size: u32) -> i32 {
+    if size == 0 {
+        v
+    } else {
+        v.rem_euclid(size as i32)
+    }
+}
+
+fn axis_contains(v: i32, low: i32, high: i32, size: u32) -> bool {
+    if size == 0 {
+        v >= low && v < high
+    } else if high - low >= size as i32 {
+        true
+    } else {
+        (v - low).rem_euclid(size as i32) < high - low
+    }
+}
+
+fn axis_overlaps(a: [i32; 2], b: [i32; 2], size: u32) -> bool {
+    if a[1] <= a[0] || b[1] <= b[0] {
+        false
+    } else if size == 0 {
+        a[0] < b[1] && b[0] < a[1]
+    } else {
+        // Two arcs of a circle overlap iff one of them starts inside the other
+        axis_contains(b[0], a[0], a[1], size) || axis_contains(a[0], b[0], b[1], size)
+    }
+}
+
+fn axis_length(a: [i32; 2], size: u32) -> i32 {
+    let length = (a[1] - a[0]).max(0);
+    if size == 0 {
+        length
+    } else {
+        length.min(size as i32)
+    }
+}
+
+// Regions going around an axis more than once are cut down to a single lap
+fn axis_one_lap(a: [i32; 2], size: u32) -> [i32; 2] {
+    if size == 0 {
+        a
+    } else {
+        [a[0], a[0] + axis_length(a, size)]
+    }
+}
+
+// What makes two arcs equal: the same length, and the same normalized start unless they cover
+// the whole circle. Without it a full lap, whose ends normalize to the same value, would look
+// like an empty arc.
+fn axis_key(a: [i32; 2], size: u32) -> [i32; 2] {
+    let length = axis_length(a, size);
+    if size == 0 {
+        a
+    } else if length == size as i32 {
+        [0, length]
+    } else {
+        [wrap(a[0], size), length]
+    }
+}
+
+// When two arcs wrap far enough to meet at both ends their intersection is two pieces, in that
+// case this is the piece which starts inside `a`
+fn axis_intersect(a: [i32; 2], b: [i32; 2], size: u32) -> Option<[i32; 2]> {
+    if !axis_overlaps(a, b, size) {
+        return None;
+    }
+    if size == 0 {
+        return Some([a[0].max(b[0]), a[1].min(b[1])]);
+    }
+    let (la, lb) = (axis_length(a, size), axis_length(b, size));
+    if la == size as i32 {
+        Some([b[0], b[0] + lb])
+    } else if axis_contains(b[0], a[0], a[1], size) {
+        let start = a[0] + (b[0] - a[0]).rem_euclid(size as i32);
+        Some([start, (a[0] + la).min(start + lb)])
+    } else {
+        let start = b[0] + (a[0] - b[0]).rem_euclid(size as i32);
+        Some([start, (b[0] + lb).min(start + la)])
+    }
+}
+
+// The shortest arc covering both. It always starts at the start of one of them.
+fn axis_bounding_box(a: [i32; 2], b: [i32; 2], size: u32) -> [i32; 2] {
+    let (la, lb) = (axis_length(a, size), axis_length(b, size));
+    if la == 0 {
+        return b;
+    }
+    if lb == 0 {
+        return a;
+    }
+    if size == 0 {
+        return [a[0].min(b[0]), a[1].max(b[1])];
+    }
+    let covering = |a: [i32; 2], la: i32, b: [i32; 2], lb: i32| {
+        let b_start = a[0] + (b[0] - a[0]).rem_euclid(size as i32);
+        let end = (a[0] + la).max(b_start + lb).min(a[0] + size as i32);
+        [a[0], end]
+    };
+    let from_a = covering(a, la, b, lb);
+    let from_b = covering(b, lb, a, la);
+    if from_a[1] - from_a[0] <= from_b[1] - from_b[0] {
+        from_a
+    } else {
+        from_b
+    }
+}
+
+fn axis_distance(v: i32, low: i32, high: i32, size: u32) -> i32 {
+    if axis_contains(v, low, high, size) {
+        0
+    } else if size == 0 {
+        (low - v).max(v - (high - 1))
+    } else {
+        (low - v).rem_euclid(size as i32).min((v - (high - 1)).rem_euclid(size as i32))
+    }
+}
+
+fn rect<const WIDTH: u32, const HEIGHT: u32>(r: &Region<Wrapping<WIDTH, HEIGHT>>) -> Region<[i32; 2]> {
+    Region::new(r.min.to_array(), r.max.to_array())
+}
+
+fn from_rect<const WIDTH: u32, const HEIGHT: u32>(r: &Region<[i32; 2]>) -> Region<Wrapping<WIDTH, HEIGHT>> {
+    Region::new(Wrapping::from_array(r.min), Wrapping::from_array(r.max))
+}
+
+fn from_axes<const WIDTH: u32, const HEIGHT: u32>(x: [i32; 2], y: [i32; 2]) -> Region<Wrapping<WIDTH, HEIGHT>> {
+    Region::new(Wrapping::new(x[0], y[0]), Wrapping::new(x[1], y[1]))
+}
+
+fn one_lap<const WIDTH: u32, const HEIGHT: u32>(r: &Region<[i32; 2]>) -> Region<Wrapping<WIDTH, HEIGHT>> {
+    from_axes(axis_one_lap([r.min[0], r.max[0]], WIDTH), axis_one_lap([r.min[1], r.max[1]], HEIGHT))
+}
+
+impl<const WIDTH: u32, const HEIGHT: u32> Point for Wrapping<WIDTH, HEIGHT> {
+    fn to_cube(&self, size: u32) -> Region<Self> {
+        one_lap(&self.to_array().to_cube(size))
+    }
+
+    fn overlap_rect(a: &Region<Self>, other: &Region<Self>) -> bool {
+        axis_overlaps([a.min.x, a.max.x], [other.min.x, other.max.x], WIDTH) &&
+        axis_overlaps([a.min.y, a.max.y], [other.min.y, other.max.y], HEIGHT)
+    }
+
+    fn intersect(a: &Region<Self>, other: &Region<Self>) -> Option<Region<Self>> {
+        let x = axis_intersect([a.min.x, a.max.x], [other.min.x, other.max.x], WIDTH)?;
+        let y = axis_intersect([a.min.y, a.max.y], [other.min.y, other.max.y], HEIGHT)?;
+        Some(from_axes(x, y))
+    }
+
+    fn bounding_box(a: &Region<Self>, other: &Region<Self>) -> Region<Self> {
+        if Self::area(a) == 0 {
+            return *other;
+        }
+        if Self::area(other) == 0 {
+            return *a;
+        }
+        from_axes(
+            axis_bounding_box([a.min.x, a.max.x], [other.min.x, other.max.x], WIDTH),
+            axis_bounding_box([a.min.y, a.max.y], [other.min.y, other.max.y], HEIGHT),
+        )
+    }
+
+    fn area(r: &Region<Self>) -> usize {
+        axis_length([r.min.x, r.max.x], WIDTH) as usize * axis_length([r.min.y, r.max.y], HEIGHT) as usize
+    }
+
+    fn split(r: &Region<Self>) -> Option<[Region<Self>; 2]> {
+        // Trim away any extra laps around the world first so both halves are distinct
+        <[i32; 2]>::split(&rect(&one_lap::<WIDTH, HEIGHT>(&rect(r)))).map(|[a, b]| [from_rect(&a), from_rect(&b)])
+    }
+
+    fn expand(r: &Region<Self>, margin: u32) -> Region<Self> {
+        one_lap(&<[i32; 2]>::expand(&rect(r), margin))
+    }
+
+    fn contained(&self, r: &Region<Self>) -> bool {
+        axis_contains(self.x, r.min.x, r.max.x, WIDTH) && axis_contains(self.y, r.min.y, r.max.y, HEIGHT)
+    }
+
+    fn check_chunk_size(chunk_size: u32) {
+        // Otherwise the last chunk before the seam would overlap the first one
+        assert!(WIDTH.is_multiple_of(chunk_size) && HEIGHT.is_multiple_of(chunk_size), "the world size must be a multiple of the chunk size");
+    }
+
+    fn chunk_index(&self, chunk_size: u32) -> (Self, usize) {
+        debug_assert!(WIDTH.is_multiple_of(chunk_size) && HEIGHT.is_multiple_of(chunk_size));
+        let (c, i) = self.normalized().to_array().chunk_index(chunk_size);
+        (Self::from_array(c), i)
+    }
+
+    fn max_unrolled_index(chunk_size: u32) -> usize {
+        <[i32; 2]>::max_unrolled_index(chunk_size)
+    }
+
+    // Chunks are reported at their normalized location, so a region which straddles the seam
+    // yields chunks from both edges of the world
+    fn chunks_in_region(r: &Region<Self>, chunk_size: u32) -> Vec<Region<Self>> {
+        let mut seen = HashSet::new();
+        <[i32; 2]>::chunks_in_region(&rect(r), chunk_size).into_iter().filter_map(|chunk| {
+            let origin = Self::from_array(chunk.min).normalized();
+            if seen.insert(origin) {
+                Some(origin.to_cube(chunk_size))
+            } else {
+                None
+            }
+        }).collect()
+    }
+
+    fn points_in_region(r: &Region<Self>) -> Vec<Self> {
+        let mut seen = HashSet::new();
+        <[i32; 2]>::points_in_region(&rect(r)).into_iter()
+            .map(|p| Self::from_array(p).normalized())
+            .filter(|p| seen.insert(*p))
+            .collect()
+    }
+
+    fn neighboors(&self) -> Vec<Self> {
+        self.to_array().neighboors().into_iter().map(|p| Self::from_array(p).normalized()).collect()
+    }
+
+    fn distance_to_region(&self, r: &Region<Self>) -> f64 {
+        let dx = axis_distance(self.x, r.min.x, r.max.x, WIDTH);
+        let dy = axis_distance(self.y, r.min.y, r.max.y, HEIGHT);
+        ((dx * dx + dy * dy) as f64).sqrt()
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        Self::new(self.x + other.x, self.y + other.y).normalized()
+    }
+
+    fn mul(&self, m: i32) -> Self {
+        Self::new(self.x * m, self.y * m)
+    }
+
+    fn div(&self, m: i32) -> Self {
+        Self::new(self.x / m, self.y / m)
+    }
+
+    fn same_region(a: &Region<Self>, other: &Region<Self>) -> bool {
+        axis_key([a.min.x, a.max.x], WIDTH) == axis_key([other.min.x, other.max.x], WIDTH) &&
+        axis_key([a.min.y, a.max.y], HEIGHT) == axis_key([other.min.y, other.max.y], HEIGHT)
+    }
+
+    fn hash_region<H: Hasher>(r: &Region<Self>, state: &mut H) {
+        for c in axis_key([r.min.x, r.max.x], WIDTH).iter().chain(&axis_key([r.min.y, r.max.y], HEIGHT)) {
+            state.write_i32(*c);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Map, WriteGuard,
+        generator::Generator,
+        region_lock::Lock,
+    };
+
+    type Cylinder = Wrapping<64, 0>;
+    type Torus = Wrapping<64, 32>;
+
+    #[test]
+    fn wrapped_points_are_equal() {
+        assert_eq!(Cylinder::new(-1, 5), Cylinder::new(63, 5));
+        assert_ne!(Cylinder::new(0, -1), Cylinder::new(0, 31));
+        assert_eq!(Torus::new(0, -1), Torus::new(64, 31));
+        assert!(Cylinder::new(63, 0).neighboors().contains(&Cylinder::new(0, 0)));
+    }
+
+    #[test]
+    fn regions_across_the_seam() {
+        let r = Region::new(Cylinder::new(-4, 0), Cylinder::new(4, 4));
+        assert!(Cylinder::new(62, 1).contained(&r));
+        assert!(Cylinder::new(2, 1).contained(&r));
+        assert!(!Cylinder::new(10, 1).contained(&r));
+        assert!(Cylinder::overlap_rect(&r, &Region::new(Cylinder::new(60, 0), Cylinder::new(61, 1))));
+        assert!(!Cylinder::overlap_rect(&r, &Region::new(Cylinder::new(8, 0), Cylinder::new(60, 4))));
+        assert_eq!(Cylinder::points_in_region(&r).len(), 32);
+        assert_eq!(Cylinder::new(6, 1).distance_to_region(&r), 3.0);
+
+        let chunks = Cylinder::chunks_in_region(&r, 8);
+        assert_eq!(chunks, vec![
+            Region::new(Cylinder::new(56, 0), Cylinder::new(64, 8)),
+            Region::new(Cylinder::new(0, 0), Cylinder::new(8, 8)),
+        ]);
+        assert_eq!(Cylinder::chunks_in_region(&Region::new(Cylinder::new(0, 0), Cylinder::new(200, 8)), 8).len(), 8);
+    }
+
+    #[test]
+    fn full_laps() {
+        let lap = Region::new(Cylinder::new(0, 0), Cylinder::new(64, 8));
+        let empty = Region::new(Cylinder::new(0, 0), Cylinder::new(0, 8));
+        assert_ne!(lap, empty);
+        assert_eq!(lap, Region::new(Cylinder::new(8, 0), Cylinder::new(72, 8)));
+        assert_eq!(Region::new(Cylinder::new(-1, 0), Cylinder::new(7, 8)), Region::new(Cylinder::new(63, 0), Cylinder::new(71, 8)));
+
+        let expanded = lap.expand(40);
+        assert_eq!(expanded.max.x - expanded.min.x, 64);
+        assert_eq!(expanded.area(), 64 * 88);
+        assert!(!empty.expand(4).contains(&Cylinder::new(10, 0)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn chunks_must_tile_the_world() {
+        let _map: Map<Cylinder, u32> = Map::new(vec![], 48, 0);
+    }
+
+    #[test]
+    fn lock_across_the_seam() {
+        let lock = Lock::new();
+        let _write_key = lock.write_region(&Region::new(Torus::new(60, -2), Torus::new(68, 2)).into());
+        assert!(lock.try_read_region(&Region::new(Torus::new(2, 0), Torus::new(3, 1)).into()).is_none());
+        assert!(lock.try_read_region(&Region::new(Torus::new(62, 30), Torus::new(63, 31)).into()).is_none());
+        assert!(lock.try_read_region(&Region::new(Torus::new(-2, 30), Torus::new(-1, 31)).into()).is_none());
+        assert!(lock.try_read_region(&Region::new(Torus::new(10, 0), Torus::new(50, 32)).into()).is_some());
+    }
+
+    #[derive(Clone)]
+    struct Column;
+
+    impl Generator<Cylinder, i32> for Column {
+        fn generate(&mut self, chunk: &mut WriteGuard<'_, Cylinder, i32>, core_region: &Region<Cylinder>, _umbra: &Region<Cylinder>) {
+            for p in Cylinder::points_in_region(core_region) {
+                *chunk.get_mut(&p).unwrap() = p.normalized().x;
+            }
+        }
+    }
+
+    #[test]
+    fn map_across_the_seam() {
+        let map: Map<Cylinder, i32> = Map::new(vec![Box::new(Column)], 8, 0);
+        map.maybe_generate(Region::new(Cylinder::new(-8, 0), Cylinder::new(8, 8)));
+        assert_eq!(*map.get(&Cylinder::new(-1, 3)), 63);
+        assert_eq!(*map.get(&Cylinder::new(127, 3)), 63);
+        let dirty = map.drain_dirty_regions();
+        assert_eq!(dirty.area(), 2 * 8 * 8);
+        assert!(dirty.contains(&Cylinder::new(-8, 0)) && dirty.contains(&Cylinder::new(7, 7)));
+
+        let region = map.region(&Region::new(Cylinder::new(-2, 0), Cylinder::new(2, 8)));
+        assert_eq!(*region.get(&Cylinder::new(62, 0)).unwrap(), 62);
+        assert_eq!(*region.get(&Cylinder::new(1, 0)).unwrap(), 1);
+    }
+}